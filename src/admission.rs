@@ -0,0 +1,185 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    str::FromStr
+};
+
+use crate::auth::{unix_millis, KeyExchange, Nonce, Proof, Secret, SessionKey, HELLO_WINDOW};
+use crate::protocol::{Handshake, MessageType, MAX_CLIENT_ID_LEN};
+
+/// Default number of player slots, like the four players an XInput system supports.
+pub(crate) const PLAYER_SLOTS: u8 = 4;
+
+/// A client ID, an IP address or an IP address and port on an allow or deny list.
+#[derive(Clone, Debug)]
+pub(crate) enum PeerPattern {
+    Addr(SocketAddr),
+    Ip(IpAddr),
+    ClientId(String),
+}
+
+impl PeerPattern {
+    fn matches(&self, addr: SocketAddr, client_id: &str) -> bool {
+        match self {
+            PeerPattern::Addr(pattern) => *pattern == addr,
+            PeerPattern::Ip(pattern) => *pattern == addr.ip(),
+            PeerPattern::ClientId(pattern) => pattern == client_id,
+        }
+    }
+}
+
+/// Anything that doesn't parse as an address is taken to be a client ID.
+impl FromStr for PeerPattern {
+    type Err = Infallible;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = pattern.parse() {
+            return Ok(PeerPattern::Addr(addr));
+        }
+        if let Ok(ip) = pattern.parse() {
+            return Ok(PeerPattern::Ip(ip));
+        }
+        Ok(PeerPattern::ClientId(pattern.to_string()))
+    }
+}
+
+/// Which clients may join. A client on the deny list is always refused, and
+/// with a non-empty allow list only the clients on it get in.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AccessList {
+    pub allow: Vec<PeerPattern>,
+    pub deny: Vec<PeerPattern>,
+}
+
+impl AccessList {
+    fn permits(&self, addr: SocketAddr, client_id: &str) -> bool {
+        let listed = |patterns: &[PeerPattern]| patterns.iter().any(|pattern| pattern.matches(addr, client_id));
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+/// Decides which peers the server lets plug in a controller.
+pub(crate) struct AdmissionPolicy {
+    room: String,
+    secret: Secret,
+    /// Refuse clients that don't offer to encrypt their session
+    require_encryption: bool,
+    access: AccessList,
+    /// How many controllers may be plugged in at once
+    max_controllers: u8,
+}
+
+impl AdmissionPolicy {
+    pub fn new(room: String, secret: Secret, require_encryption: bool, access: AccessList, max_controllers: u8) -> Self {
+        AdmissionPolicy { room, secret, require_encryption, access, max_controllers }
+    }
+
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    pub fn max_controllers(&self) -> u8 {
+        self.max_controllers
+    }
+
+    /// Checks a client's handshake, returning its proof if it may join or the
+    /// reason sent back to the client if it is refused.
+    pub fn check(&self, addr: SocketAddr, handshake: &Handshake) -> Result<Proof, String> {
+        if !matches!(MessageType::from(handshake.role), MessageType::Client) {
+            return Err(format!("{} did not introduce itself as a client", addr));
+        }
+        if handshake.room != self.room {
+            return Err(format!("room {:?} is not hosted here", handshake.room));
+        }
+        if handshake.client_id.is_empty() || handshake.client_id.len() > MAX_CLIENT_ID_LEN {
+            return Err(format!("invalid client ID {:?}", handshake.client_id));
+        }
+        let proof = match handshake.proof {
+            Some(proof) if self.secret.verify_hello(&handshake.room, &handshake.client_id, handshake.controller, &proof) => proof,
+            _ => return Err("the session secret does not match".to_string()),
+        };
+        if !proof.is_fresh(unix_millis()) {
+            return Err("the handshake is too old, or the clocks of client and host are too far apart".to_string());
+        }
+        if self.require_encryption && proof.public_key.is_none() {
+            return Err("the host only accepts encrypted sessions".to_string());
+        }
+        // Checked after the proof, so nobody can learn what is on the lists by trying IDs
+        if !self.access.permits(addr, &handshake.client_id) {
+            return Err(format!("client {:?} at {} is not allowed to join", handshake.client_id, addr));
+        }
+        Ok(proof)
+    }
+
+    /// Answers an admitted handshake for player `slot`, returning the proof for
+    /// the accept and the key for the client's session. The session is
+    /// encrypted whenever the client offered to.
+    pub fn accept(&self, hello: &Proof, slot: u8) -> Result<(Proof, SessionKey), String> {
+        if hello.public_key.is_none() {
+            let accept = self.secret.accept(hello, slot, None);
+            return Ok((accept, self.secret.session(hello, &accept, None)));
+        }
+        let exchange = KeyExchange::new();
+        let accept = self.secret.accept(hello, slot, Some(&exchange));
+        let shared = exchange.agree(hello).ok_or("the offered public key is unusable")?;
+        Ok((accept, self.secret.session(hello, &accept, Some(&shared))))
+    }
+}
+
+/// Player slots handed out by client ID, one per controller the server is
+/// willing to plug in. A client keeps its slot after it leaves, so it gets the
+/// same one back when it returns, unless a new client needed it in the meantime.
+pub(crate) struct PlayerSlots {
+    owners: Vec<Option<String>>,
+}
+
+impl PlayerSlots {
+    pub fn new(count: u8) -> Self {
+        PlayerSlots { owners: vec![None; count as usize] }
+    }
+
+    /// Finds a slot for `client_id`, preferring the one it had before, then one
+    /// nobody had, then any other. Slots `in_use` by a plugged in controller
+    /// are never handed out, not even to the client ID that owns them.
+    pub fn assign(&mut self, client_id: &str, in_use: impl Fn(u8) -> bool) -> Result<u8, String> {
+        let free = |index: &usize| !in_use(*index as u8 + 1);
+        let index = self
+            .owners
+            .iter()
+            .position(|owner| owner.as_deref() == Some(client_id))
+            .filter(free)
+            .or_else(|| (0..self.owners.len()).filter(free).find(|index| self.owners[*index].is_none()))
+            .or_else(|| (0..self.owners.len()).find(free))
+            .ok_or_else(|| format!("the host is full, all {} controllers are in use", self.owners.len()))?;
+        for owner in self.owners.iter_mut().filter(|owner| owner.as_deref() == Some(client_id)) {
+            *owner = None;
+        }
+        self.owners[index] = Some(client_id.to_string());
+        Ok(index as u8 + 1)
+    }
+}
+
+/// Hello nonces the server accepted, so a captured handshake can't be replayed
+/// to take over a client's session. Hellos outside the `HELLO_WINDOW` are
+/// refused anyway, so each nonce is only kept until its hello leaves it.
+pub(crate) struct HelloHistory {
+    /// When each nonce can be forgotten, in milliseconds since the Unix epoch
+    expiry: HashMap<Nonce, u64>,
+}
+
+impl HelloHistory {
+    pub fn new() -> Self {
+        HelloHistory { expiry: HashMap::new() }
+    }
+
+    pub fn seen(&self, hello: &Proof) -> bool {
+        self.expiry.contains_key(&hello.nonce)
+    }
+
+    pub fn remember(&mut self, hello: &Proof) {
+        let now = unix_millis();
+        self.expiry.retain(|_, expiry| *expiry >= now);
+        self.expiry.insert(hello.nonce, hello.sent_at + HELLO_WINDOW.as_millis() as u64);
+    }
+}
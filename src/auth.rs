@@ -0,0 +1,355 @@
+use anyhow::{Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce
+};
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rkyv::{Archive, Deserialize, Serialize};
+use sha2::Sha256;
+use std::{fs, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+
+use crate::protocol::ControllerKind;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub(crate) const NONCE_LEN: usize = 16;
+pub(crate) const MAC_LEN: usize = 32;
+const AEAD_NONCE_LEN: usize = 24;
+/// How far the timestamp of a client's hello may be from the host's clock,
+/// either way. Older hellos are refused, so a captured one can only be replayed
+/// within this window, and hosts remember the hellos they accepted until then.
+pub(crate) const HELLO_WINDOW: Duration = Duration::from_secs(120);
+
+pub(crate) type Nonce = [u8; NONCE_LEN];
+pub(crate) type Tag = [u8; MAC_LEN];
+
+/// Proof that the sender knows the session secret, sent along with a client's
+/// handshake and the host's accept. A `public_key` asks for, or agrees to, an
+/// encrypted session and is covered by the MAC so nobody in between can swap it.
+#[derive(Archive, Deserialize, Serialize, Debug, Clone, Copy)]
+#[archive(check_bytes)]
+pub(crate) struct Proof {
+    pub nonce: Nonce,
+    /// When the proof was made, in milliseconds since the Unix epoch
+    pub sent_at: u64,
+    pub public_key: Option<[u8; 32]>,
+    pub mac: Tag,
+}
+
+impl Proof {
+    fn new(exchange: Option<&KeyExchange>) -> Self {
+        let public_key = exchange.map(|exchange| exchange.public.to_bytes());
+        Proof { nonce: nonce(), sent_at: unix_millis(), public_key, mac: [0; MAC_LEN] }
+    }
+
+    fn public_key_bytes(&self) -> &[u8] {
+        self.public_key.as_ref().map_or(&[], |key| key.as_slice())
+    }
+
+    /// Whether the proof was made within `HELLO_WINDOW` of `now`.
+    pub fn is_fresh(&self, now: u64) -> bool {
+        now.abs_diff(self.sent_at) <= HELLO_WINDOW.as_millis() as u64
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch.
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Lets a client carry its controller over into a new session, for example
+/// after its address changed. `mac` is made with the key of the session named
+/// by `previous`, which only that session's client knows.
+#[derive(Archive, Deserialize, Serialize, Debug, Clone, Copy)]
+#[archive(check_bytes)]
+pub(crate) struct Resume {
+    pub previous: Nonce,
+    pub mac: Tag,
+}
+
+pub(crate) fn nonce() -> Nonce {
+    rand::random()
+}
+
+/// Our half of an X25519 exchange, thrown away once the session key is derived.
+pub(crate) struct KeyExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        KeyExchange { secret, public }
+    }
+
+    /// Combines our half with the key in the peer's proof. Fails if the peer
+    /// didn't send one, or sent one that would make the result predictable.
+    pub fn agree(self, peer: &Proof) -> Option<SharedSecret> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer.public_key?));
+        shared.was_contributory().then_some(shared)
+    }
+}
+
+/// The pre-shared secret a host and its clients agree on out of band.
+#[derive(Clone)]
+pub(crate) struct Secret {
+    key: Vec<u8>,
+}
+
+impl Secret {
+    /// Reads the secret from `--secret` or the first line of `--secret-file`.
+    pub fn load(secret: Option<String>, secret_file: Option<&Path>) -> Result<Self> {
+        let key = match (secret, secret_file) {
+            (Some(secret), _) => secret,
+            (None, Some(path)) => fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the secret from {}", path.display()))?
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            (None, None) => String::new(),
+        };
+        Ok(Secret { key: key.into_bytes() })
+    }
+
+    /// An empty secret still protects against corrupted datagrams, but lets
+    /// anyone who can reach the host join.
+    pub fn is_empty(&self) -> bool {
+        self.key.is_empty()
+    }
+
+    /// Each part is prefixed with its length, so no two different sets of
+    /// parts are signed the same.
+    fn mac(&self, label: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(label);
+        for part in parts {
+            mac.update(&(part.len() as u32).to_be_bytes());
+            mac.update(part);
+        }
+        mac
+    }
+
+    /// Signs the handshake of client `client_id` for `room`, asking for a
+    /// `controller` and offering an encrypted session if `exchange` is set.
+    pub fn hello(&self, room: &str, client_id: &str, controller: ControllerKind, exchange: Option<&KeyExchange>) -> Proof {
+        let mut proof = Proof::new(exchange);
+        proof.mac = self.hello_mac(room, client_id, controller, &proof).finalize().into_bytes().into();
+        proof
+    }
+
+    pub fn verify_hello(&self, room: &str, client_id: &str, controller: ControllerKind, proof: &Proof) -> bool {
+        self.hello_mac(room, client_id, controller, proof).verify_slice(&proof.mac).is_ok()
+    }
+
+    fn hello_mac(&self, room: &str, client_id: &str, controller: ControllerKind, proof: &Proof) -> HmacSha256 {
+        let sent_at = proof.sent_at.to_be_bytes();
+        self.mac(b"hello", &[room.as_bytes(), client_id.as_bytes(), &[controller as u8], &proof.nonce, &sent_at, proof.public_key_bytes()])
+    }
+
+    /// Signs the host's answer to the handshake carrying `hello`, binding the
+    /// two together along with the player slot the client got.
+    pub fn accept(&self, hello: &Proof, slot: u8, exchange: Option<&KeyExchange>) -> Proof {
+        let mut proof = Proof::new(exchange);
+        proof.mac = self.accept_mac(hello, slot, &proof).finalize().into_bytes().into();
+        proof
+    }
+
+    pub fn verify_accept(&self, hello: &Proof, slot: u8, accept: &Proof) -> bool {
+        self.accept_mac(hello, slot, accept).verify_slice(&accept.mac).is_ok()
+    }
+
+    fn accept_mac(&self, hello: &Proof, slot: u8, accept: &Proof) -> HmacSha256 {
+        let sent_at = accept.sent_at.to_be_bytes();
+        self.mac(b"accept", &[&hello.mac, &[slot], &accept.nonce, &sent_at, accept.public_key_bytes()])
+    }
+
+    /// Derives the key for one client's session from both handshake nonces, so
+    /// datagrams from an earlier session can't be replayed into a new one. With
+    /// a key exchange the session is encrypted and the result of the exchange
+    /// goes into the key too, so even someone who knows the secret can't read it.
+    pub fn session(&self, hello: &Proof, accept: &Proof, shared: Option<&SharedSecret>) -> SessionKey {
+        let shared_bytes = shared.map_or(&[][..], |shared| shared.as_bytes());
+        let key = self.mac(b"session", &[&hello.nonce, &accept.nonce, shared_bytes]).finalize().into_bytes().into();
+        SessionKey { key, encrypted: shared.is_some() }
+    }
+}
+
+/// What a client keeps from a session to resume it later.
+#[derive(Clone, Copy)]
+pub(crate) struct Ticket {
+    /// Nonce of the handshake that started the session
+    pub hello: Nonce,
+    pub key: SessionKey,
+}
+
+/// Key that protects the datagrams of one client session, either by signing
+/// them or by encrypting them.
+#[derive(Clone, Copy)]
+pub(crate) struct SessionKey {
+    key: [u8; 32],
+    encrypted: bool,
+}
+
+impl SessionKey {
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    fn mac(&self, header: &[u8], payload: &[u8]) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(header);
+        mac.update(payload);
+        mac
+    }
+
+    /// Signs the handshake `hello` to show it continues this session.
+    pub fn resume(&self, previous: Nonce, hello: &Proof) -> Resume {
+        let mac = self.mac(b"resume", &hello.mac).finalize().into_bytes().into();
+        Resume { previous, mac }
+    }
+
+    pub fn verify_resume(&self, resume: &Resume, hello: &Proof) -> bool {
+        self.mac(b"resume", &hello.mac).verify_slice(&resume.mac).is_ok()
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.key.into())
+    }
+
+    /// Protects `payload`, with `header` authenticated alongside it. Signed
+    /// payloads get a MAC appended, encrypted ones are prefixed with a random
+    /// nonce, which is long enough to never repeat for the same key.
+    pub fn seal(&self, header: &[u8], payload: &[u8]) -> Vec<u8> {
+        if !self.encrypted {
+            let mut sealed = payload.to_vec();
+            sealed.extend_from_slice(&self.mac(header, payload).finalize().into_bytes());
+            return sealed;
+        }
+        let nonce: [u8; AEAD_NONCE_LEN] = rand::random();
+        let ciphertext = self
+            .cipher()
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: payload, aad: header })
+            .expect("Encrypting a datagram cannot fail");
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Reverses `seal`, or returns `None` if the datagram was tampered with or
+    /// protected with a different key.
+    pub fn open(&self, header: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if !self.encrypted {
+            let (payload, tag) = sealed.split_at(sealed.len().checked_sub(MAC_LEN)?);
+            return self.mac(header, payload).verify_slice(tag).is_ok().then(|| payload.to_vec());
+        }
+        if sealed.len() < AEAD_NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(AEAD_NONCE_LEN);
+        self.cipher().decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: header }).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PROTOCOL_VERSION;
+
+    fn secret(key: &str) -> Secret {
+        Secret { key: key.as_bytes().to_vec() }
+    }
+
+    fn signed_session(secret: &Secret) -> SessionKey {
+        let hello = secret.hello("room", "client", ControllerKind::Xbox360, None);
+        let accept = secret.accept(&hello, 1, None);
+        secret.session(&hello, &accept, None)
+    }
+
+    /// Checks that `key` opens what it sealed, but nothing that was changed on
+    /// the way or sealed by `other`.
+    fn assert_sealed(key: &SessionKey, other: &SessionKey) {
+        let header = [PROTOCOL_VERSION, 3];
+        let sealed = key.seal(&header, b"input");
+        assert_eq!(key.open(&header, &sealed).as_deref(), Some(&b"input"[..]));
+
+        for index in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 0x01;
+            assert_eq!(key.open(&header, &tampered), None, "flipped a bit in byte {}", index);
+        }
+        assert_eq!(key.open(&[PROTOCOL_VERSION, 12], &sealed), None);
+        assert_eq!(key.open(&header, &sealed[..sealed.len() - 1]), None);
+        assert_eq!(key.open(&header, &[]), None);
+        assert_eq!(other.open(&header, &sealed), None);
+    }
+
+    #[test]
+    fn signed_sessions_reject_tampering_and_other_keys() {
+        let shared = secret("shared");
+        assert_sealed(&signed_session(&shared), &signed_session(&shared));
+        assert_sealed(&signed_session(&shared), &signed_session(&secret("other")));
+    }
+
+    #[test]
+    fn encrypted_sessions_reject_tampering_and_other_keys() {
+        let shared = secret("shared");
+        let client = KeyExchange::new();
+        let hello = shared.hello("room", "client", ControllerKind::Xbox360, Some(&client));
+        let host = KeyExchange::new();
+        let accept = shared.accept(&hello, 1, Some(&host));
+        let host_key = shared.session(&hello, &accept, Some(&host.agree(&hello).unwrap()));
+        let client_key = shared.session(&hello, &accept, Some(&client.agree(&accept).unwrap()));
+        assert!(host_key.is_encrypted() && client_key.is_encrypted());
+
+        let sealed = client_key.seal(&[PROTOCOL_VERSION, 3], b"input");
+        assert_eq!(host_key.open(&[PROTOCOL_VERSION, 3], &sealed).as_deref(), Some(&b"input"[..]));
+        assert!(!sealed.windows(5).any(|window| window == b"input"));
+
+        // Knowing the secret and both handshakes isn't enough without the exchange
+        assert_sealed(&client_key, &shared.session(&hello, &accept, None));
+        assert_sealed(&client_key, &signed_session(&shared));
+    }
+
+    #[test]
+    fn hellos_only_verify_with_what_they_were_signed_for() {
+        let shared = secret("shared");
+        let hello = shared.hello("room", "client", ControllerKind::DualShock4, None);
+        assert!(shared.verify_hello("room", "client", ControllerKind::DualShock4, &hello));
+        assert!(!secret("other").verify_hello("room", "client", ControllerKind::DualShock4, &hello));
+        assert!(!shared.verify_hello("other", "client", ControllerKind::DualShock4, &hello));
+        assert!(!shared.verify_hello("room", "other", ControllerKind::DualShock4, &hello));
+        assert!(!shared.verify_hello("room", "client", ControllerKind::Xbox360, &hello));
+        let backdated = Proof { sent_at: hello.sent_at - 1, ..hello };
+        assert!(!shared.verify_hello("room", "client", ControllerKind::DualShock4, &backdated));
+        let swapped = Proof { public_key: Some(KeyExchange::new().public.to_bytes()), ..hello };
+        assert!(!shared.verify_hello("room", "client", ControllerKind::DualShock4, &swapped));
+    }
+
+    #[test]
+    fn accepts_only_verify_for_their_hello_and_slot() {
+        let shared = secret("shared");
+        let hello = shared.hello("room", "client", ControllerKind::Xbox360, None);
+        let accept = shared.accept(&hello, 2, None);
+        assert!(shared.verify_accept(&hello, 2, &accept));
+        assert!(!secret("other").verify_accept(&hello, 2, &accept));
+        assert!(!shared.verify_accept(&hello, 1, &accept));
+        let other_hello = shared.hello("room", "client", ControllerKind::Xbox360, None);
+        assert!(!shared.verify_accept(&other_hello, 2, &accept));
+    }
+
+    #[test]
+    fn hellos_go_stale_outside_the_window() {
+        let hello = secret("shared").hello("room", "client", ControllerKind::Xbox360, None);
+        let window = HELLO_WINDOW.as_millis() as u64;
+        assert!(hello.is_fresh(hello.sent_at));
+        assert!(hello.is_fresh(hello.sent_at + window));
+        assert!(hello.is_fresh(hello.sent_at - window));
+        assert!(!hello.is_fresh(hello.sent_at + window + 1));
+        assert!(!hello.is_fresh(hello.sent_at - window - 1));
+    }
+}
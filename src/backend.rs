@@ -0,0 +1,489 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use std::{collections::VecDeque, sync::{Arc, Mutex}};
+
+use crate::key_mapper::UserInput;
+use crate::protocol::ControllerKind;
+
+/// A virtual controller that the server drives with a client's inputs.
+pub(crate) trait ControllerBackend: Send {
+    fn plugin(&mut self) -> Result<()>;
+    fn update(&mut self, input: &UserInput) -> Result<()>;
+    fn unplug(&mut self) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum BackendKind {
+    /// ViGEmBus virtual Xbox 360 and DualShock 4 pads (Windows only)
+    #[cfg(windows)]
+    Vigem,
+    /// uinput virtual evdev gamepads (Linux only)
+    #[cfg(target_os = "linux")]
+    Uinput,
+    /// Records every controller event in memory and logs it to stdout
+    Recording,
+}
+
+impl Default for BackendKind {
+    fn default() -> Self {
+        #[cfg(windows)]
+        return BackendKind::Vigem;
+        #[cfg(target_os = "linux")]
+        return BackendKind::Uinput;
+        #[cfg(not(any(windows, target_os = "linux")))]
+        return BackendKind::Recording;
+    }
+}
+
+/// Shared state for a backend, used by the server to create one controller per client.
+pub(crate) enum Backend {
+    #[cfg(windows)]
+    Vigem(Arc<vigem_client::Client>),
+    #[cfg(target_os = "linux")]
+    Uinput,
+    Recording(Recorder),
+}
+
+impl Backend {
+    pub fn connect(kind: BackendKind) -> Result<Self> {
+        match kind {
+            #[cfg(windows)]
+            BackendKind::Vigem => Ok(Backend::Vigem(Arc::new(vigem_client::Client::connect()?))),
+            #[cfg(target_os = "linux")]
+            BackendKind::Uinput => Ok(Backend::Uinput),
+            BackendKind::Recording => Ok(Backend::Recording(Recorder::default())),
+        }
+    }
+
+    /// Creates a `kind` controller for player `slot`. ViGEm can't pick the
+    /// XInput user index, so there the slot only decides the order pads are
+    /// plugged in, and an Xbox 360 pad that Windows numbers differently is logged.
+    pub fn create(&self, slot: u8, kind: ControllerKind) -> Box<dyn ControllerBackend> {
+        match self {
+            #[cfg(windows)]
+            Backend::Vigem(client) => Box::new(vigem::VigemController::new(client.clone(), slot, kind)),
+            #[cfg(target_os = "linux")]
+            Backend::Uinput => Box::new(uinput::UinputController::new(slot, kind)),
+            Backend::Recording(recorder) => Box::new(RecordingController {
+                slot,
+                kind,
+                events: recorder.events.clone()
+            }),
+        }
+    }
+}
+
+/// Scales a stick axis to the 0-255 range of a DualShock 4, centered on 128.
+/// `flip` turns an XInput Y axis, which points up, into one that points down.
+#[cfg(any(windows, target_os = "linux"))]
+fn ds4_axis(value: i16, flip: bool) -> u8 {
+    let value = if flip { -(value as i32) } else { value as i32 };
+    ((value.min(i16::MAX as i32) >> 8) + 128) as u8
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ControllerEvent {
+    Plugin(ControllerKind),
+    Update(UserInput),
+    Unplug,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RecordedEvent {
+    pub slot: u8,
+    pub event: ControllerEvent,
+}
+
+/// How many events a recording backend keeps. Older ones are dropped so a
+/// long running server doesn't grow without bound, they were logged anyway.
+const RECORDED_EVENTS: usize = 1024;
+
+/// Event log shared by every controller created from a recording backend.
+#[derive(Default)]
+pub(crate) struct Recorder {
+    events: Arc<Mutex<VecDeque<RecordedEvent>>>,
+}
+
+impl Recorder {
+    /// The most recent events, oldest first.
+    #[cfg(test)]
+    pub fn events(&self) -> Vec<RecordedEvent> {
+        self.events.lock().unwrap().iter().copied().collect()
+    }
+}
+
+pub(crate) struct RecordingController {
+    slot: u8,
+    kind: ControllerKind,
+    events: Arc<Mutex<VecDeque<RecordedEvent>>>,
+}
+
+impl RecordingController {
+    fn record(&self, event: ControllerEvent) {
+        println!("Controller {}: {:?}", self.slot, event);
+        let mut events = self.events.lock().unwrap();
+        if events.len() == RECORDED_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(RecordedEvent { slot: self.slot, event });
+    }
+}
+
+impl ControllerBackend for RecordingController {
+    fn plugin(&mut self) -> Result<()> {
+        self.record(ControllerEvent::Plugin(self.kind));
+        Ok(())
+    }
+
+    fn update(&mut self, input: &UserInput) -> Result<()> {
+        self.record(ControllerEvent::Update(*input));
+        Ok(())
+    }
+
+    fn unplug(&mut self) -> Result<()> {
+        self.record(ControllerEvent::Unplug);
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod vigem {
+    use anyhow::Result;
+    use std::sync::Arc;
+    use vigem_client::{Client, DS4Report, DualShock4Wired, TargetId, XButtons, XGamepad, Xbox360Wired};
+
+    use super::{ds4_axis, ControllerBackend};
+    use crate::key_mapper::UserInput;
+    use crate::protocol::ControllerKind;
+
+    /// XUSB button bits and the DualShock 4 button each one is reported as.
+    const DS4_BUTTONS: [(u16, u16); 10] = [
+        (XButtons::X, 1 << 4),       // Square
+        (XButtons::A, 1 << 5),       // Cross
+        (XButtons::B, 1 << 6),       // Circle
+        (XButtons::Y, 1 << 7),       // Triangle
+        (XButtons::LB, 1 << 8),      // L1
+        (XButtons::RB, 1 << 9),      // R1
+        (XButtons::BACK, 1 << 12),   // Share
+        (XButtons::START, 1 << 13),  // Options
+        (XButtons::LTHUMB, 1 << 14), // L3
+        (XButtons::RTHUMB, 1 << 15), // R3
+    ];
+    /// L2 and R2 also report a press on top of the analog value
+    const DS4_TRIGGER_LEFT: u16 = 1 << 10;
+    const DS4_TRIGGER_RIGHT: u16 = 1 << 11;
+    const DS4_SPECIAL_PS: u8 = 1 << 0;
+    const DS4_DPAD_NONE: u16 = 8;
+
+    /// The DualShock 4 reports its dpad as a hat, numbered clockwise from north.
+    fn ds4_dpad(buttons: u16) -> u16 {
+        let pressed = |button| buttons & button != 0;
+        match (pressed(XButtons::UP), pressed(XButtons::RIGHT), pressed(XButtons::DOWN), pressed(XButtons::LEFT)) {
+            (true, false, _, false) => 0,
+            (true, true, _, _) => 1,
+            (false, true, false, _) => 2,
+            (_, true, true, _) => 3,
+            (_, false, true, false) => 4,
+            (_, _, true, true) => 5,
+            (false, _, false, true) => 6,
+            (true, _, _, true) => 7,
+            _ => DS4_DPAD_NONE,
+        }
+    }
+
+    fn ds4_report(input: &UserInput) -> DS4Report {
+        let mut buttons = ds4_dpad(input.buttons);
+        for (bit, ds4_bit) in DS4_BUTTONS {
+            if input.buttons & bit != 0 {
+                buttons |= ds4_bit;
+            }
+        }
+        if input.ltrigger > 0 {
+            buttons |= DS4_TRIGGER_LEFT;
+        }
+        if input.rtrigger > 0 {
+            buttons |= DS4_TRIGGER_RIGHT;
+        }
+        DS4Report {
+            thumb_lx: ds4_axis(input.lx, false),
+            thumb_ly: ds4_axis(input.ly, true),
+            thumb_rx: ds4_axis(input.rx, false),
+            thumb_ry: ds4_axis(input.ry, true),
+            buttons,
+            special: if input.buttons & XButtons::GUIDE != 0 { DS4_SPECIAL_PS } else { 0 },
+            trigger_l: input.ltrigger,
+            trigger_r: input.rtrigger,
+        }
+    }
+
+    enum Target {
+        Xbox360(Xbox360Wired<Arc<Client>>),
+        DualShock4(DualShock4Wired<Arc<Client>>),
+    }
+
+    pub(crate) struct VigemController {
+        /// Player slot the client was told it got
+        slot: u8,
+        target: Target,
+    }
+
+    impl VigemController {
+        pub fn new(client: Arc<Client>, slot: u8, kind: ControllerKind) -> Self {
+            let target = match kind {
+                ControllerKind::Xbox360 => Target::Xbox360(Xbox360Wired::new(client, TargetId::XBOX360_WIRED)),
+                ControllerKind::DualShock4 => Target::DualShock4(DualShock4Wired::new(client, TargetId::DUALSHOCK4_WIRED)),
+            };
+            VigemController { slot, target }
+        }
+    }
+
+    impl ControllerBackend for VigemController {
+        fn plugin(&mut self) -> Result<()> {
+            match &mut self.target {
+                Target::Xbox360(target) => {
+                    target.plugin()?;
+                    target.wait_ready()?;
+                    // Windows hands out the XInput user index itself, so the player number
+                    // the client sees on its pad can differ from the slot it was told
+                    match target.get_user_index() {
+                        Ok(index) if index + 1 != self.slot as u32 => {
+                            eprintln!("Controller for player {} became XInput player {}", self.slot, index + 1);
+                        }
+                        Ok(_) => {}
+                        Err(e) => eprintln!("Failed to read the XInput player of controller {}: {:?}", self.slot, e),
+                    }
+                }
+                Target::DualShock4(target) => {
+                    target.plugin()?;
+                    target.wait_ready()?;
+                }
+            }
+            Ok(())
+        }
+
+        fn update(&mut self, input: &UserInput) -> Result<()> {
+            match &mut self.target {
+                Target::Xbox360(target) => {
+                    let gamepad = XGamepad {
+                        thumb_lx: input.lx,
+                        thumb_ly: input.ly,
+                        thumb_rx: input.rx,
+                        thumb_ry: input.ry,
+                        left_trigger: input.ltrigger,
+                        right_trigger: input.rtrigger,
+                        buttons: XButtons { raw: input.buttons }
+                    };
+                    target.update(&gamepad)?;
+                }
+                Target::DualShock4(target) => target.update(&ds4_report(input))?,
+            }
+            Ok(())
+        }
+
+        fn unplug(&mut self) -> Result<()> {
+            match &mut self.target {
+                Target::Xbox360(target) => target.unplug()?,
+                Target::DualShock4(target) => target.unplug()?,
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod uinput {
+    use anyhow::{Context, Result};
+    use evdev::{
+        uinput::{VirtualDevice, VirtualDeviceBuilder},
+        AbsInfo, AbsoluteAxisType, AttributeSet, BusType, EventType, InputEvent, InputId, Key, UinputAbsSetup
+    };
+
+    use super::{ds4_axis, ControllerBackend};
+    use crate::key_mapper::UserInput;
+    use crate::protocol::ControllerKind;
+
+    // Identify as the real pad so SDL and Steam pick the right mapping, and lay
+    // out axes and buttons the same way the kernel's xpad and hid-playstation
+    // drivers do. xpad reports X and Y as BTN_NORTH and BTN_WEST, while
+    // hid-playstation puts Square and Triangle at BTN_WEST and BTN_NORTH.
+    fn input_id(kind: ControllerKind) -> InputId {
+        match kind {
+            ControllerKind::Xbox360 => InputId::new(BusType::BUS_USB, 0x045E, 0x028E, 0x0110),
+            ControllerKind::DualShock4 => InputId::new(BusType::BUS_USB, 0x054C, 0x05C4, 0x0100),
+        }
+    }
+
+    /// XUSB button bits (see `controller_map`) and the evdev key xpad reports each one as.
+    const XPAD_BUTTONS: [(u16, Key); 11] = [
+        (0x0010, Key::BTN_START),
+        (0x0020, Key::BTN_SELECT),
+        (0x0040, Key::BTN_THUMBL),
+        (0x0080, Key::BTN_THUMBR),
+        (0x0100, Key::BTN_TL),
+        (0x0200, Key::BTN_TR),
+        (0x0400, Key::BTN_MODE),
+        (0x1000, Key::BTN_SOUTH),
+        (0x2000, Key::BTN_EAST),
+        (0x4000, Key::BTN_NORTH),
+        (0x8000, Key::BTN_WEST),
+    ];
+
+    /// The same bits as hid-playstation reports them, where X is Square and Y is Triangle.
+    const DS4_BUTTONS: [(u16, Key); 11] = [
+        (0x0010, Key::BTN_START),
+        (0x0020, Key::BTN_SELECT),
+        (0x0040, Key::BTN_THUMBL),
+        (0x0080, Key::BTN_THUMBR),
+        (0x0100, Key::BTN_TL),
+        (0x0200, Key::BTN_TR),
+        (0x0400, Key::BTN_MODE),
+        (0x1000, Key::BTN_SOUTH),
+        (0x2000, Key::BTN_EAST),
+        (0x4000, Key::BTN_WEST),
+        (0x8000, Key::BTN_NORTH),
+    ];
+
+    fn buttons(kind: ControllerKind) -> &'static [(u16, Key)] {
+        match kind {
+            ControllerKind::Xbox360 => &XPAD_BUTTONS,
+            ControllerKind::DualShock4 => &DS4_BUTTONS,
+        }
+    }
+
+    const DPAD_UP: u16 = 0x0001;
+    const DPAD_DOWN: u16 = 0x0002;
+    const DPAD_LEFT: u16 = 0x0004;
+    const DPAD_RIGHT: u16 = 0x0008;
+
+    pub(crate) struct UinputController {
+        slot: u8,
+        kind: ControllerKind,
+        device: Option<VirtualDevice>,
+    }
+
+    impl UinputController {
+        pub fn new(slot: u8, kind: ControllerKind) -> Self {
+            UinputController { slot, kind, device: None }
+        }
+
+        /// evdev's Y axes point down while XInput's point up
+        fn stick_value(&self, value: i16, flip: bool) -> i32 {
+            match self.kind {
+                ControllerKind::Xbox360 if flip => (-(value as i32)).min(i16::MAX as i32),
+                ControllerKind::Xbox360 => value as i32,
+                ControllerKind::DualShock4 => ds4_axis(value, flip) as i32,
+            }
+        }
+    }
+
+    fn stick_axis(axis: AbsoluteAxisType, kind: ControllerKind) -> UinputAbsSetup {
+        let info = match kind {
+            ControllerKind::Xbox360 => AbsInfo::new(0, i16::MIN as i32, i16::MAX as i32, 16, 128, 0),
+            ControllerKind::DualShock4 => AbsInfo::new(128, 0, u8::MAX as i32, 0, 0, 0),
+        };
+        UinputAbsSetup::new(axis, info)
+    }
+
+    fn trigger_axis(axis: AbsoluteAxisType) -> UinputAbsSetup {
+        UinputAbsSetup::new(axis, AbsInfo::new(0, 0, u8::MAX as i32, 0, 0, 0))
+    }
+
+    fn hat_axis(axis: AbsoluteAxisType) -> UinputAbsSetup {
+        UinputAbsSetup::new(axis, AbsInfo::new(0, -1, 1, 0, 0, 0))
+    }
+
+    fn hat_value(buttons: u16, negative: u16, positive: u16) -> i32 {
+        (buttons & positive != 0) as i32 - (buttons & negative != 0) as i32
+    }
+
+    fn abs_event(axis: AbsoluteAxisType, value: i32) -> InputEvent {
+        InputEvent::new(EventType::ABSOLUTE, axis.0, value)
+    }
+
+    impl ControllerBackend for UinputController {
+        fn plugin(&mut self) -> Result<()> {
+            let mut keys: AttributeSet<Key> = buttons(self.kind).iter().map(|(_, key)| *key).collect();
+            if self.kind == ControllerKind::DualShock4 {
+                // L2 and R2 also report a press on top of the analog value
+                keys.insert(Key::BTN_TL2);
+                keys.insert(Key::BTN_TR2);
+            }
+            let device = VirtualDeviceBuilder::new()
+                .context("Failed to open /dev/uinput")?
+                .name(&format!("KTC Virtual {} Controller (Player {})", self.kind, self.slot))
+                .input_id(input_id(self.kind))
+                .with_keys(&keys)?
+                .with_absolute_axis(&stick_axis(AbsoluteAxisType::ABS_X, self.kind))?
+                .with_absolute_axis(&stick_axis(AbsoluteAxisType::ABS_Y, self.kind))?
+                .with_absolute_axis(&stick_axis(AbsoluteAxisType::ABS_RX, self.kind))?
+                .with_absolute_axis(&stick_axis(AbsoluteAxisType::ABS_RY, self.kind))?
+                .with_absolute_axis(&trigger_axis(AbsoluteAxisType::ABS_Z))?
+                .with_absolute_axis(&trigger_axis(AbsoluteAxisType::ABS_RZ))?
+                .with_absolute_axis(&hat_axis(AbsoluteAxisType::ABS_HAT0X))?
+                .with_absolute_axis(&hat_axis(AbsoluteAxisType::ABS_HAT0Y))?
+                .build()?;
+            self.device = Some(device);
+            Ok(())
+        }
+
+        fn update(&mut self, input: &UserInput) -> Result<()> {
+            let mut events = vec![
+                abs_event(AbsoluteAxisType::ABS_X, self.stick_value(input.lx, false)),
+                abs_event(AbsoluteAxisType::ABS_Y, self.stick_value(input.ly, true)),
+                abs_event(AbsoluteAxisType::ABS_RX, self.stick_value(input.rx, false)),
+                abs_event(AbsoluteAxisType::ABS_RY, self.stick_value(input.ry, true)),
+                abs_event(AbsoluteAxisType::ABS_Z, input.ltrigger as i32),
+                abs_event(AbsoluteAxisType::ABS_RZ, input.rtrigger as i32),
+                abs_event(AbsoluteAxisType::ABS_HAT0X, hat_value(input.buttons, DPAD_LEFT, DPAD_RIGHT)),
+                abs_event(AbsoluteAxisType::ABS_HAT0Y, hat_value(input.buttons, DPAD_UP, DPAD_DOWN)),
+            ];
+            for &(bit, key) in buttons(self.kind) {
+                events.push(InputEvent::new(EventType::KEY, key.code(), (input.buttons & bit != 0) as i32));
+            }
+            if self.kind == ControllerKind::DualShock4 {
+                events.push(InputEvent::new(EventType::KEY, Key::BTN_TL2.code(), (input.ltrigger > 0) as i32));
+                events.push(InputEvent::new(EventType::KEY, Key::BTN_TR2.code(), (input.rtrigger > 0) as i32));
+            }
+
+            let Some(device) = self.device.as_mut() else {
+                anyhow::bail!("Controller is not plugged in");
+            };
+
+            device.emit(&events)?;
+            Ok(())
+        }
+
+        fn unplug(&mut self) -> Result<()> {
+            // Dropping the device destroys it
+            self.device = None;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorder_keeps_the_latest_events_of_every_controller() {
+        let backend = Backend::connect(BackendKind::Recording).unwrap();
+        let Backend::Recording(recorder) = &backend else {
+            unreachable!("asked for the recording backend");
+        };
+        let mut first = backend.create(1, ControllerKind::Xbox360);
+        let mut second = backend.create(2, ControllerKind::DualShock4);
+        first.plugin().unwrap();
+        second.plugin().unwrap();
+        for lx in 0..RECORDED_EVENTS as i16 {
+            first.update(&UserInput { lx, ..UserInput::default() }).unwrap();
+        }
+        second.unplug().unwrap();
+
+        let events = recorder.events();
+        assert_eq!(events.len(), RECORDED_EVENTS);
+        // Both plugins and the first update were pushed out
+        assert_eq!(events[0], RecordedEvent { slot: 1, event: ControllerEvent::Update(UserInput { lx: 1, ..UserInput::default() }) });
+        assert_eq!(events[RECORDED_EVENTS - 1], RecordedEvent { slot: 2, event: ControllerEvent::Unplug });
+    }
+}
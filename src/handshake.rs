@@ -0,0 +1,302 @@
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{net::UdpSocket, time::{self, Instant}};
+
+use crate::auth::{KeyExchange, Secret, Ticket};
+use crate::protocol::{self, ControllerKind, Handshake, Welcome, MessageKind, MessageType, Packet, PeerErrors, ProtocolError, MAX_PAYLOAD, RELAY_KEEPALIVE_INTERVAL};
+
+/// How many times a handshake step is attempted, and how long to wait for an
+/// answer before retrying. The wait doubles after every attempt up to `max_backoff`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RetryPolicy {
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(1 << attempt.min(16)).min(self.max_backoff)
+    }
+}
+
+/// Waits for the next datagram from `peer` until `deadline`, dropping anything
+/// malformed or from someone else. A version mismatch is fatal since retrying
+/// can't fix it.
+async fn recv_from_peer(conn: &UdpSocket, peer: SocketAddr, deadline: Instant, errors: &mut PeerErrors) -> Result<Option<Packet>> {
+    let mut buffer = [0; MAX_PAYLOAD];
+    loop {
+        let (bytes_recv, addr) = match time::timeout_at(deadline, protocol::recv_datagram(conn, &mut buffer)).await {
+            Ok(result) => result?,
+            Err(_) => return Ok(None),
+        };
+        if addr != peer {
+            continue;
+        }
+        match protocol::decode(&buffer[..bytes_recv]) {
+            Ok(packet) => return Ok(Some(packet)),
+            Err(e @ ProtocolError::VersionMismatch(_)) => bail!("{} rejected our handshake: {}", peer, e),
+            Err(e) => errors.record(addr, &e),
+        }
+    }
+}
+
+/// Sends `request` to `peer` until `accept` turns one of its answers into a
+/// result, or the retry policy runs out. Answers `accept` has no use for are
+/// counted as unexpected.
+pub(crate) async fn request<T>(
+    conn: &UdpSocket,
+    peer: SocketAddr,
+    request: &[u8],
+    policy: &RetryPolicy,
+    errors: &mut PeerErrors,
+    mut accept: impl FnMut(Packet) -> Result<Option<T>>,
+) -> Result<T> {
+    for attempt in 0..policy.attempts {
+        conn.send_to(request, peer).await?;
+        let deadline = Instant::now() + policy.delay(attempt);
+        while let Some(packet) = recv_from_peer(conn, peer, deadline, errors).await? {
+            let kind = packet.kind;
+            match accept(packet)? {
+                Some(result) => return Ok(result),
+                None => errors.record(peer, &ProtocolError::UnexpectedKind(kind)),
+            }
+        }
+    }
+    bail!("{} did not answer after {} attempts", peer, policy.attempts)
+}
+
+/// Turns a rejection from the relay into an error. Anything else is left to the caller.
+pub(crate) fn check_rejected(packet: &Packet) -> Result<()> {
+    if packet.kind == MessageKind::RelayReject {
+        let reason: String = packet.message()?;
+        bail!("The relay refused our registration: {}", reason);
+    }
+    Ok(())
+}
+
+/// The handshake a host registers `room` with. Hosts keep resending it instead
+/// of a plain keepalive, so a relay that restarted or expired them takes them
+/// back.
+pub(crate) fn host_handshake(room: &str) -> Bytes {
+    protocol::encode(MessageKind::Handshake, &Handshake { role: MessageType::Host as u8, room: room.to_string(), client_id: String::new(), controller: ControllerKind::default(), proof: None, resume: None })
+}
+
+/// Registers the host with the relay. The relay follows its ack with the
+/// room's client list, which is returned if it overtook the ack and is
+/// otherwise left for the server loop.
+pub(crate) async fn register_host(conn: &UdpSocket, relay_addr: SocketAddr, room: &str, policy: &RetryPolicy, errors: &mut PeerErrors) -> Result<Vec<SocketAddr>> {
+    let clients = request(conn, relay_addr, &host_handshake(room), policy, errors, |packet| {
+        check_rejected(&packet)?;
+        match packet.kind {
+            MessageKind::RelayAck => Ok(Some(Vec::new())),
+            MessageKind::ClientList => Ok(Some(packet.message::<Vec<SocketAddr>>()?)),
+            _ => Ok(None),
+        }
+    })
+    .await
+    .map_err(|e| e.context("Failed to register with the relay server"))?;
+    println!("Registered room {:?} with relay {}", room, relay_addr);
+    Ok(clients)
+}
+
+/// Registers a client with the relay and waits for it to announce the room's host.
+pub(crate) async fn register_client(conn: &UdpSocket, relay_addr: SocketAddr, room: &str, policy: &RetryPolicy, errors: &mut PeerErrors) -> Result<SocketAddr> {
+    let handshake = protocol::encode(MessageKind::Handshake, &Handshake { role: MessageType::Client as u8, room: room.to_string(), client_id: String::new(), controller: ControllerKind::default(), proof: None, resume: None });
+    let host = request(conn, relay_addr, &handshake, policy, errors, |packet| {
+        check_rejected(&packet)?;
+        match packet.kind {
+            MessageKind::RelayAck => Ok(Some(None)),
+            MessageKind::HostInfo => Ok(Some(Some(packet.message::<SocketAddr>()?))),
+            _ => Ok(None),
+        }
+    })
+    .await
+    .map_err(|e| e.context("Failed to register with the relay server"))?;
+    if let Some(host) = host {
+        return Ok(host);
+    }
+
+    // The room has no host yet. Re-registering doubles as a keepalive and makes
+    // the relay repeat the host's address in case the first one got lost.
+    println!("Waiting for a host to open room {:?}", room);
+    loop {
+        let deadline = Instant::now() + RELAY_KEEPALIVE_INTERVAL;
+        while let Some(packet) = recv_from_peer(conn, relay_addr, deadline, errors).await? {
+            check_rejected(&packet)?;
+            match packet.kind {
+                MessageKind::HostInfo => return Ok(packet.message::<SocketAddr>()?),
+                MessageKind::RelayAck => {}
+                kind => errors.record(relay_addr, &ProtocolError::UnexpectedKind(kind)),
+            }
+        }
+        conn.send_to(&handshake, relay_addr).await?;
+    }
+}
+
+/// How a client asks its host to let it join.
+pub(crate) struct JoinOptions {
+    pub room: String,
+    /// Lets the host give us the same player slot every time
+    pub client_id: String,
+    /// Controller the host should plug in for us
+    pub controller: ControllerKind,
+    pub secret: Secret,
+    /// Only join if the host agrees to encrypt the session
+    pub encrypt: bool,
+}
+
+/// Introduces a client to its host and waits for the host's admission
+/// decision, returning the ticket for the new session. A `previous` session
+/// is resumed if the host still has it. Probes the host sends meanwhile are
+/// answered and recorded in `progress` so punching can make progress on both
+/// sides.
+pub(crate) async fn hello(
+    conn: &UdpSocket,
+    host: SocketAddr,
+    options: &JoinOptions,
+    previous: Option<&Ticket>,
+    policy: &RetryPolicy,
+    progress: &mut PunchProgress,
+    errors: &mut PeerErrors,
+) -> Result<Ticket> {
+    let secret = &options.secret;
+    let exchange = options.encrypt.then(KeyExchange::new);
+    // Retries reuse the nonce so the host can tell them apart from a new session
+    let proof = secret.hello(&options.room, &options.client_id, options.controller, exchange.as_ref());
+    let resume = previous.map(|previous| previous.key.resume(previous.hello, &proof));
+    let handshake = protocol::encode(MessageKind::Handshake, &Handshake {
+        role: MessageType::Client as u8,
+        room: options.room.clone(),
+        client_id: options.client_id.clone(),
+        controller: options.controller,
+        proof: Some(proof),
+        resume,
+    });
+    let ack = protocol::encode_empty(MessageKind::PunchAck);
+
+    for attempt in 0..policy.attempts {
+        conn.send_to(&handshake, host).await?;
+        let deadline = Instant::now() + policy.delay(attempt);
+        while let Some(packet) = recv_from_peer(conn, host, deadline, errors).await? {
+            match packet.kind {
+                // Only a host that knows the secret can sign the accept
+                MessageKind::Accept => match packet.message::<Welcome>() {
+                    Ok(Welcome { proof: accept, slot }) if secret.verify_accept(&proof, slot, &accept) => {
+                        let shared = match exchange {
+                            Some(exchange) => Some(exchange.agree(&accept).ok_or_else(|| anyhow!("Host {} did not agree to encrypt the session", host))?),
+                            None => None,
+                        };
+                        println!("Joined host {} as player {}", host, slot);
+                        return Ok(Ticket { hello: proof.nonce, key: secret.session(&proof, &accept, shared.as_ref()) });
+                    }
+                    Ok(_) => errors.record(host, &ProtocolError::Unauthenticated),
+                    Err(e) => errors.record(host, &e),
+                },
+                MessageKind::Reject => {
+                    let reason: String = packet.message()?;
+                    bail!("Host {} refused to let us join: {}", host, reason);
+                }
+                MessageKind::Punch => {
+                    conn.send_to(&ack, host).await?;
+                    progress.record_probe();
+                }
+                kind => errors.record(host, &ProtocolError::UnexpectedKind(kind)),
+            }
+        }
+    }
+    bail!("Failed to join host {}: no answer after {} attempts", host, policy.attempts)
+}
+
+/// How far hole punching towards a peer has come.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Reachability {
+    /// Nothing has made it through yet
+    Probing,
+    /// The peer acknowledged one of our probes, but none of its probes reached us
+    Outbound,
+    /// One of the peer's probes reached us, but it hasn't acknowledged ours
+    Inbound,
+    /// Packets make it through in both directions
+    Established,
+}
+
+/// Tracks which directions have been confirmed while punching towards `peer`
+/// and logs every change.
+pub(crate) struct PunchProgress {
+    peer: SocketAddr,
+    inbound: bool,
+    outbound: bool,
+    pub attempts: u32,
+}
+
+impl PunchProgress {
+    pub fn new(peer: SocketAddr) -> Self {
+        PunchProgress { peer, inbound: false, outbound: false, attempts: 0 }
+    }
+
+    pub fn state(&self) -> Reachability {
+        match (self.outbound, self.inbound) {
+            (false, false) => Reachability::Probing,
+            (true, false) => Reachability::Outbound,
+            (false, true) => Reachability::Inbound,
+            (true, true) => Reachability::Established,
+        }
+    }
+
+    fn update(&mut self, change: impl FnOnce(&mut Self)) {
+        let before = self.state();
+        change(self);
+        let after = self.state();
+        if before != after {
+            println!("Punching {}: {:?} -> {:?}", self.peer, before, after);
+        }
+    }
+
+    /// The peer's probe reached us.
+    pub fn record_probe(&mut self) {
+        self.update(|progress| progress.inbound = true);
+    }
+
+    /// The peer acknowledged one of our probes.
+    pub fn record_ack(&mut self) {
+        self.update(|progress| progress.outbound = true);
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.state() == Reachability::Established
+    }
+}
+
+/// Exchanges punch packets with `peer` until both of us have received a probe
+/// from the other and had one of ours acknowledged, which opens the NAT
+/// mappings on both sides.
+pub(crate) async fn punch(conn: &UdpSocket, peer: SocketAddr, policy: &RetryPolicy, mut progress: PunchProgress, errors: &mut PeerErrors) -> Result<()> {
+    let probe = protocol::encode_empty(MessageKind::Punch);
+    let ack = protocol::encode_empty(MessageKind::PunchAck);
+    progress.attempts = 0;
+
+    while progress.attempts < policy.attempts {
+        conn.send_to(&probe, peer).await?;
+        let deadline = Instant::now() + policy.delay(progress.attempts);
+        progress.attempts += 1;
+        while let Some(packet) = recv_from_peer(conn, peer, deadline, errors).await? {
+            match packet.kind {
+                MessageKind::Punch => {
+                    conn.send_to(&ack, peer).await?;
+                    progress.record_probe();
+                }
+                MessageKind::PunchAck => progress.record_ack(),
+                kind => errors.record(peer, &ProtocolError::UnexpectedKind(kind)),
+            }
+            if progress.is_established() {
+                return Ok(());
+            }
+        }
+    }
+    bail!(
+        "Failed to punch through to {}: still {:?} after {} attempts",
+        peer, progress.state(), policy.attempts
+    )
+}
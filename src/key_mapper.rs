@@ -1,11 +1,12 @@
-use anyhow::{Result, bail};
+use anyhow::{anyhow, bail, Result};
 use device_query::{DeviceQuery, DeviceState};
 use rkyv::{Archive, Serialize, Deserialize};
 use std::sync::OnceLock;
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
-use toml::de;
+use std::time::{Duration, Instant};
+use toml::{Table, Value};
 use device_query::keymap::Keycode;
 
 fn key_hashmap() -> &'static HashMap<&'static str, Keycode> {
@@ -127,12 +128,171 @@ fn key_hashmap() -> &'static HashMap<&'static str, Keycode> {
     })
 }
 
-#[derive(Clone, Copy)]
+/// A thumbstick axis, in the order `UserInput` stores them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Axis {
+    LX,
+    LY,
+    RX,
+    RY,
+}
+
+impl Axis {
+    const ALL: [Axis; 4] = [Axis::LX, Axis::LY, Axis::RX, Axis::RY];
+
+    fn parse(name: &str) -> Option<Axis> {
+        Axis::ALL.into_iter().find(|axis| format!("{:?}", axis) == name)
+    }
+}
+
+/// How an axis resolves both of its directions being held at once, known as
+/// simultaneous opposing cardinal directions (SOCD).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum SocdMode {
+    /// The directions cancel out
+    Neutral,
+    /// The direction pressed most recently wins
+    #[default]
+    LastInputWins,
+    /// The direction that was held first wins until it is released
+    FirstInputWins,
+    /// The positive direction always wins
+    PositiveWins,
+    /// The negative direction always wins
+    NegativeWins,
+}
+
+impl SocdMode {
+    fn parse(name: &str) -> Option<SocdMode> {
+        match name {
+            "neutral" => Some(SocdMode::Neutral),
+            "last" => Some(SocdMode::LastInputWins),
+            "first" => Some(SocdMode::FirstInputWins),
+            "positive" => Some(SocdMode::PositiveWins),
+            "negative" => Some(SocdMode::NegativeWins),
+            _ => None,
+        }
+    }
+
+    /// Picks the axis value from the `values` of the held keys bound to it,
+    /// in the order they were pressed.
+    fn resolve(self, values: &[i16]) -> i16 {
+        let last = |positive: bool| values.iter().rev().find(|value| (**value > 0) == positive).copied();
+        let (Some(positive), Some(negative)) = (last(true), last(false)) else {
+            return values.last().copied().unwrap_or(0);
+        };
+        match self {
+            SocdMode::Neutral => 0,
+            SocdMode::LastInputWins => values[values.len() - 1],
+            SocdMode::FirstInputWins => values[0],
+            SocdMode::PositiveWins => positive,
+            SocdMode::NegativeWins => negative,
+        }
+    }
+}
+
+/// The shape a stick's travel is limited to when two axes are pushed at once.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Gate {
+    /// Each axis goes its full way, so diagonals reach further than cardinals
+    #[default]
+    Square,
+    /// Diagonals are pulled in so they reach as far as the stronger axis alone
+    Circle,
+}
+
+impl Gate {
+    fn parse(name: &str) -> Option<Gate> {
+        match name {
+            "square" => Some(Gate::Square),
+            "circle" => Some(Gate::Circle),
+            _ => None,
+        }
+    }
+
+    fn apply(self, x: i16, y: i16) -> (i16, i16) {
+        if self == Gate::Square || x == 0 || y == 0 {
+            return (x, y);
+        }
+        let (x, y) = (x as f64, y as f64);
+        let scale = x.abs().max(y.abs()) / x.hypot(y);
+        ((x * scale).round() as i16, (y * scale).round() as i16)
+    }
+}
+
+/// How a ramping axis eases between where it was and where its keys want it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Curve {
+    Linear,
+    /// Starts and ends slowly, like a thumb pushing a real stick
+    Ease,
+}
+
+/// Moves an axis towards the value its keys ask for over time instead of
+/// snapping to it, both when keys are pressed and when they are released.
+#[derive(Clone, Copy, Debug)]
+struct Ramp {
+    curve: Curve,
+    /// How long going from rest to full tilt takes, shorter moves take less
+    full_tilt: Duration,
+}
+
+impl Ramp {
+    /// Parses a `{ curve = "linear" | "ease", ms = 150 }` table.
+    fn parse(axis: &str, value: &Value) -> Result<Ramp> {
+        let Some(table) = value.as_table() else {
+            bail!("Ramp for {} needs to be a table like {{ curve = \"ease\", ms = 150 }}", axis);
+        };
+        let curve = match table.get("curve").map(|curve| curve.as_str()) {
+            None | Some(Some("linear")) => Curve::Linear,
+            Some(Some("ease")) => Curve::Ease,
+            Some(_) => bail!("Ramp curve for {} not supported (use linear or ease)", axis),
+        };
+        let Some(ms) = table.get("ms").and_then(Value::as_integer).filter(|ms| (1..=10_000).contains(ms)) else {
+            bail!("Ramp for {} needs a duration between 1 and 10000 ms", axis);
+        };
+        Ok(Ramp { curve, full_tilt: Duration::from_millis(ms as u64) })
+    }
+
+    /// Moves `motion` towards `target`, starting over from wherever the axis
+    /// is whenever the target changes.
+    fn advance(&self, motion: &mut AxisMotion, target: i16, now: Instant) -> i16 {
+        if motion.target != target {
+            *motion = AxisMotion { from: motion.value, target, since: now, value: motion.value };
+        }
+        let (from, target) = (motion.from as f64, target as f64);
+        let duration = self.full_tilt.as_secs_f64() * (target - from).abs() / FULL_TILT as f64;
+        let progress = if duration > 0.0 { (now.duration_since(motion.since).as_secs_f64() / duration).min(1.0) } else { 1.0 };
+        let eased = match self.curve {
+            Curve::Linear => progress,
+            Curve::Ease => progress * progress * (3.0 - 2.0 * progress),
+        };
+        motion.value = (from + (target - from) * eased).round() as i16;
+        motion.value
+    }
+}
+
+/// Where a ramping axis is, and the move it is making.
+#[derive(Clone, Copy, Debug)]
+struct AxisMotion {
+    from: i16,
+    target: i16,
+    since: Instant,
+    value: i16,
+}
+
+impl AxisMotion {
+    fn at_rest(now: Instant) -> Self {
+        AxisMotion { from: 0, target: 0, since: now, value: 0 }
+    }
+}
+
+/// How far a stick binding tilts its axis unless it asks for less.
+const FULL_TILT: i16 = 29999;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum ControllerAction {
-    ThumbstickLX(i16),
-    ThumbstickLY(i16),
-    ThumbstickRX(i16),
-    ThumbstickRY(i16),
+    Thumbstick(Axis, i16),
     LTrigger(u8),
     RTrigger(u8),
     Button(u16),
@@ -142,14 +302,14 @@ fn controller_map() -> &'static HashMap<&'static str, ControllerAction> {
     static CONTROLLER_MAP: OnceLock<HashMap<&'static str, ControllerAction>> = OnceLock::new();
     CONTROLLER_MAP.get_or_init(|| {
         HashMap::from([
-            ("LX+", ControllerAction::ThumbstickLX(29999)),
-            ("LX-", ControllerAction::ThumbstickLX(-29999)),
-            ("LY+", ControllerAction::ThumbstickLY(29999)),
-            ("LY-", ControllerAction::ThumbstickLY(-29999)),
-            ("RX+", ControllerAction::ThumbstickRX(29999)),
-            ("RX-", ControllerAction::ThumbstickRX(-29999)),
-            ("RY+", ControllerAction::ThumbstickRY(29999)),
-            ("RY-", ControllerAction::ThumbstickRY(-29999)),
+            ("LX+", ControllerAction::Thumbstick(Axis::LX, FULL_TILT)),
+            ("LX-", ControllerAction::Thumbstick(Axis::LX, -FULL_TILT)),
+            ("LY+", ControllerAction::Thumbstick(Axis::LY, FULL_TILT)),
+            ("LY-", ControllerAction::Thumbstick(Axis::LY, -FULL_TILT)),
+            ("RX+", ControllerAction::Thumbstick(Axis::RX, FULL_TILT)),
+            ("RX-", ControllerAction::Thumbstick(Axis::RX, -FULL_TILT)),
+            ("RY+", ControllerAction::Thumbstick(Axis::RY, FULL_TILT)),
+            ("RY-", ControllerAction::Thumbstick(Axis::RY, -FULL_TILT)),
             ("UP", ControllerAction::Button(1)),
             ("DOWN", ControllerAction::Button(2)),
             ("LEFT", ControllerAction::Button(4)),
@@ -165,19 +325,61 @@ fn controller_map() -> &'static HashMap<&'static str, ControllerAction> {
             ("B", ControllerAction::Button(8192)),
             ("X", ControllerAction::Button(16384)),
             ("Y", ControllerAction::Button(32768)),
-            ("LTRIGGER", 255),
-            ("RTRIGGER", 255)
+            ("LTRIGGER", ControllerAction::LTrigger(255)),
+            ("RTRIGGER", ControllerAction::RTrigger(255))
         ])
     })
 }
 
+/// Parses a binding's action, which may carry a magnitude after an `@`: how
+/// far a stick is tilted, from above 0 up to 1, or how far a trigger is
+/// pulled, from 1 to 255. `LX+@0.4` walks, `RTRIGGER@128` pulls halfway.
+fn parse_action(binding: &str) -> Result<ControllerAction> {
+    let (name, magnitude) = match binding.split_once('@') {
+        Some((name, magnitude)) => (name, Some(magnitude)),
+        None => (binding, None),
+    };
+    let Some(action) = controller_map().get(name) else {
+        bail!("Controller action not supported: {}", binding);
+    };
+    let Some(magnitude) = magnitude else {
+        return Ok(*action);
+    };
+    let pull = || magnitude.parse::<u8>().ok().filter(|pull| *pull > 0);
+    match *action {
+        ControllerAction::Thumbstick(axis, direction) => {
+            // Too small a tilt would round to the center, which doesn't count as either direction
+            let tilt = magnitude.parse::<f64>().ok().filter(|tilt| *tilt <= 1.0).map(|tilt| (direction as f64 * tilt).round() as i16);
+            let Some(tilt) = tilt.filter(|tilt| tilt.signum() == direction.signum()) else {
+                bail!("Stick magnitude needs to be above 0 and at most 1: {}", binding);
+            };
+            Ok(ControllerAction::Thumbstick(axis, tilt))
+        }
+        ControllerAction::LTrigger(_) => pull()
+            .map(ControllerAction::LTrigger)
+            .ok_or_else(|| anyhow!("Trigger magnitude needs to be between 1 and 255: {}", binding)),
+        ControllerAction::RTrigger(_) => pull()
+            .map(ControllerAction::RTrigger)
+            .ok_or_else(|| anyhow!("Trigger magnitude needs to be between 1 and 255: {}", binding)),
+        ControllerAction::Button(_) => bail!("Buttons don't take a magnitude: {}", binding),
+    }
+}
+
 #[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
 pub(crate) enum ClientMessage {
-    Hearbeat,
-    Input(UserInput)
+    /// Tells the host we are still there. The host echoes `ping` and `sent_at`
+    /// so we can measure the link.
+    Hearbeat { ping: u64, sent_at: u64 },
+    /// Input state, numbered so the host can tell when datagrams got reordered
+    Input { sequence: u64, input: UserInput },
+    /// The client is shutting down and its controller can be unplugged
+    Disconnect
 }
 
-#[derive(Archive, PartialEq, Deserialize, Serialize, Clone, Copy)]
+/// The default is the neutral state, with nothing pressed.
+#[derive(Archive, PartialEq, Deserialize, Serialize, Clone, Copy, Debug, Default)]
+#[archive(check_bytes)]
 pub(crate) struct UserInput {
     pub lx: i16,
     pub ly: i16,
@@ -188,69 +390,139 @@ pub(crate) struct UserInput {
     pub buttons: u16
 }
 
+/// Turns the keys held on this machine into controller input.
 pub(crate) struct KeyMapper {
-    config: HashMap<Keycode, ControllerAction>,
+    mapping: Mapping,
     device_state: DeviceState
 }
 
 impl KeyMapper {
     pub fn new(config_path: &Path) -> Result<Self> {
-        let config_string: String = fs::read_to_string(config_path)?;
-        let config_stringmap: HashMap<String, String> = de::from_str(&config_string)?;
+        let mapping = Mapping::parse(&fs::read_to_string(config_path)?)?;
+        Ok(KeyMapper { mapping, device_state: DeviceState::new() })
+    }
+
+    pub fn get_input(&mut self) -> Result<UserInput> {
+        Ok(self.mapping.input(self.device_state.get_keys(), Instant::now()))
+    }
+}
+
+/// What a config turns held keys into. The config holds:
+/// - `KEY = "ACTION"` bindings, e.g. `W = "LY+"` or `Q = "LTRIGGER@128"`
+/// - an optional `[socd]` table resolving opposite directions, e.g. `LX = "neutral"`
+/// - an optional `[gate]` table shaping stick diagonals, e.g. `left = "circle"`
+/// - an optional `[ramp]` table easing axes, e.g. `LX = { curve = "ease", ms = 150 }`
+struct Mapping {
+    config: HashMap<Keycode, ControllerAction>,
+    socd: [SocdMode; 4],
+    ramps: [Option<Ramp>; 4],
+    motions: [AxisMotion; 4],
+    /// Gates of the left and right stick
+    gates: [Gate; 2],
+    /// Keys held at the last poll, oldest press first
+    held: Vec<Keycode>,
+}
+
+impl Mapping {
+    fn parse(config_string: &str) -> Result<Self> {
+        let config_table: Table = config_string.parse()?;
 
         let mut config: HashMap<Keycode, ControllerAction> = HashMap::new();
-        for (key, action) in config_stringmap.into_iter() {
-            match (key_hashmap().get(key.as_str()), controller_map().get(action.as_str())) {
-                (Some(keycode), Some(controller_action)) => {
-                    config.insert(*keycode, *controller_action);
-                },
-                (None, _) => {
-                    bail!("Key not supported: {}", key);
-                },
-                (_, None) => {
-                    bail!("Controller action not supported: {}", action) ;
+        let mut socd = [SocdMode::default(); 4];
+        let mut gates = [Gate::default(); 2];
+        let mut ramps = [None; 4];
+        for (key, value) in config_table.into_iter() {
+            let action = match value {
+                Value::String(action) => action,
+                Value::Table(modes) if key == "socd" => {
+                    for (axis, mode) in modes.into_iter() {
+                        match (Axis::parse(&axis), mode.as_str().and_then(SocdMode::parse)) {
+                            (Some(axis), Some(mode)) => socd[axis as usize] = mode,
+                            (None, _) => bail!("Axis not supported: {}", axis),
+                            (_, None) => bail!("SOCD mode not supported: {} (use neutral, last, first, positive or negative)", mode),
+                        }
+                    }
+                    continue;
                 }
-                _ => {}
-            }
+                Value::Table(sticks) if key == "gate" => {
+                    for (stick, gate) in sticks.into_iter() {
+                        let index = match stick.as_str() {
+                            "left" => 0,
+                            "right" => 1,
+                            _ => bail!("Stick not supported: {} (use left or right)", stick),
+                        };
+                        let Some(gate) = gate.as_str().and_then(Gate::parse) else {
+                            bail!("Gate not supported: {} (use square or circle)", gate);
+                        };
+                        gates[index] = gate;
+                    }
+                    continue;
+                }
+                Value::Table(axes) if key == "ramp" => {
+                    for (axis, ramp) in axes.iter() {
+                        let Some(index) = Axis::parse(axis) else {
+                            bail!("Axis not supported: {}", axis);
+                        };
+                        ramps[index as usize] = Some(Ramp::parse(axis, ramp)?);
+                    }
+                    continue;
+                }
+                _ => bail!("Binding for {} needs to be a controller action", key),
+            };
+            let Some(keycode) = key_hashmap().get(key.as_str()) else {
+                bail!("Key not supported: {}", key);
+            };
+            config.insert(*keycode, parse_action(&action)?);
         }
 
-        let device_state = DeviceState::new();
-
-        Ok(KeyMapper {
+        Ok(Mapping {
             config,
-            device_state
+            socd,
+            ramps,
+            motions: [AxisMotion::at_rest(Instant::now()); 4],
+            gates,
+            held: Vec::new(),
         })
     }
 
-    pub fn get_input(&self) -> Result<UserInput> {
-        let keys: Vec<Keycode> = self.device_state.get_keys();
-        
-        let mut lx: i16 = 0;
-        let mut ly: i16 = 0;
-        let mut rx: i16 = 0;
-        let mut ry: i16 = 0;
+    /// Maps the `keys` held at `now`. The order they come in doesn't matter,
+    /// the order they were pressed in is tracked across calls.
+    fn input(&mut self, keys: Vec<Keycode>, now: Instant) -> UserInput {
+        // Keep the order keys were pressed in across polls, which SOCD resolution needs
+        self.held.retain(|keycode| keys.contains(keycode));
+        for keycode in keys {
+            if !self.held.contains(&keycode) {
+                self.held.push(keycode);
+            }
+        }
+
+        let mut sticks: [Vec<i16>; 4] = Default::default();
         let mut ltrigger: u8 = 0;
         let mut rtrigger: u8 = 0;
         let mut buttons: u16 = 0;
 
-        for keycode in keys {
-            match self.config.get(&keycode) {
-                Some(action) => {
-                    match action {
-                        ControllerAction::ThumbstickLX(direction) => lx = *direction,
-                        ControllerAction::ThumbstickLY(direction) => ly = *direction,
-                        ControllerAction::ThumbstickRX(direction) => rx = *direction,
-                        ControllerAction::ThumbstickRY(direction) => ry = *direction,
-                        ControllerAction::LTrigger(magnitude) => ltrigger = magnitude,
-                        ControllerAction::RTrigger(magnitude) => rtrigger = magnitude,
-                        ControllerAction::Button(button) => buttons |= button
-                    }
+        for keycode in &self.held {
+            if let Some(action) = self.config.get(keycode) {
+                match action {
+                    ControllerAction::Thumbstick(axis, direction) => sticks[*axis as usize].push(*direction),
+                    ControllerAction::LTrigger(magnitude) => ltrigger = *magnitude,
+                    ControllerAction::RTrigger(magnitude) => rtrigger = *magnitude,
+                    ControllerAction::Button(button) => buttons |= button
                 }
-                None => {}
             }
         }
-        
-        Ok(UserInput {
+        let [lx, ly, rx, ry] = Axis::ALL.map(|axis| {
+            let index = axis as usize;
+            let target = self.socd[index].resolve(&sticks[index]);
+            match &self.ramps[index] {
+                Some(ramp) => ramp.advance(&mut self.motions[index], target, now),
+                None => target,
+            }
+        });
+        let (lx, ly) = self.gates[0].apply(lx, ly);
+        let (rx, ry) = self.gates[1].apply(rx, ry);
+
+        UserInput {
             lx,
             ly,
             rx,
@@ -258,6 +530,77 @@ impl KeyMapper {
             ltrigger,
             rtrigger,
             buttons
-        })    
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WASD: &str = "W = \"LY+\"\nA = \"LX-\"\nS = \"LY-\"\nD = \"LX+\"\n";
+
+    fn lx_after_polls(socd: &str, polls: &[&[Keycode]]) -> Vec<i16> {
+        let mut mapping = Mapping::parse(&format!("{}[socd]\nLX = \"{}\"\n", WASD, socd)).unwrap();
+        let now = Instant::now();
+        polls.iter().map(|keys| mapping.input(keys.to_vec(), now).lx).collect()
+    }
+
+    #[test]
+    fn socd_modes_follow_press_order_across_polls() {
+        // D, then A joins, then D is let go and pressed again. Every poll lists A
+        // first, so only the press order tracked across polls tells them apart.
+        let polls: &[&[Keycode]] = &[&[Keycode::D], &[Keycode::A, Keycode::D], &[Keycode::A], &[Keycode::A, Keycode::D]];
+        let (left, right) = (-FULL_TILT, FULL_TILT);
+        assert_eq!(lx_after_polls("last", polls), [right, left, left, right]);
+        assert_eq!(lx_after_polls("first", polls), [right, right, left, left]);
+        assert_eq!(lx_after_polls("neutral", polls), [right, 0, left, 0]);
+        assert_eq!(lx_after_polls("positive", polls), [right, right, left, right]);
+        assert_eq!(lx_after_polls("negative", polls), [right, left, left, left]);
+    }
+
+    #[test]
+    fn circle_gate_pulls_in_diagonals() {
+        let mut mapping = Mapping::parse(&format!("{}[gate]\nleft = \"circle\"\n", WASD)).unwrap();
+        let now = Instant::now();
+        let diagonal = (FULL_TILT as f64 / 2f64.sqrt()).round() as i16;
+        let input = mapping.input(vec![Keycode::W, Keycode::D], now);
+        assert_eq!((input.lx, input.ly), (diagonal, diagonal));
+        let input = mapping.input(vec![Keycode::S, Keycode::A], now);
+        assert_eq!((input.lx, input.ly), (-diagonal, -diagonal));
+        let input = mapping.input(vec![Keycode::W], now);
+        assert_eq!((input.lx, input.ly), (0, FULL_TILT));
+
+        let mut square = Mapping::parse(WASD).unwrap();
+        let input = square.input(vec![Keycode::W, Keycode::D], now);
+        assert_eq!((input.lx, input.ly), (FULL_TILT, FULL_TILT));
+    }
+
+    #[test]
+    fn magnitudes_are_bounded() {
+        assert_eq!(parse_action("LX+@1").unwrap(), ControllerAction::Thumbstick(Axis::LX, FULL_TILT));
+        assert_eq!(parse_action("LY-@0.5").unwrap(), ControllerAction::Thumbstick(Axis::LY, -15000));
+        assert_eq!(parse_action("RTRIGGER@128").unwrap(), ControllerAction::RTrigger(128));
+        assert_eq!(parse_action("LTRIGGER@255").unwrap(), ControllerAction::LTrigger(255));
+        for binding in ["LX+@0", "LX+@0.00001", "LX-@0.00001", "LX+@1.1", "LX+@NaN", "LX+@-0.5", "RTRIGGER@0", "RTRIGGER@256", "LTRIGGER@0.5", "A@1"] {
+            assert!(parse_action(binding).is_err(), "{} should not parse", binding);
+        }
+    }
+
+    #[test]
+    fn ramps_reach_full_tilt_in_their_duration() {
+        let linear = Ramp { curve: Curve::Linear, full_tilt: Duration::from_millis(100) };
+        let ease = Ramp { curve: Curve::Ease, full_tilt: Duration::from_millis(100) };
+        let start = Instant::now();
+        for (ramp, quarter) in [(linear, 7500), (ease, 4687)] {
+            let mut motion = AxisMotion::at_rest(start);
+            let progress = [0, 25, 50, 100, 150].map(|ms| ramp.advance(&mut motion, FULL_TILT, start + Duration::from_millis(ms)));
+            assert_eq!(progress, [0, quarter, 15000, FULL_TILT, FULL_TILT]);
+        }
+
+        // Half the distance takes half the time
+        let mut motion = AxisMotion::at_rest(start);
+        assert_eq!(linear.advance(&mut motion, -15000, start), 0);
+        assert_eq!(linear.advance(&mut motion, -15000, start + Duration::from_millis(50)), -15000);
+    }
+}
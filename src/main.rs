@@ -1,19 +1,35 @@
-use std::{char::MAX, collections::HashMap, sync::Arc, time::Duration};
-use anyhow::{Result, ensure};
-use tokio::{net::UdpSocket, select, sync::mpsc, time};
-use bytes::{Buf, Bytes, BufMut, BytesMut};
-use clap::{Parser, Command};
-use std::net::{IpAddr, SocketAddr};
-use rkyv::{ser::serializers::AllocSerializer, Archive, Deserialize, Serialize};
-use vigem_client::Client;
-
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::Arc, time::Duration};
+use anyhow::{Context, Result, bail, ensure};
+use tokio::{net::UdpSocket, sync::mpsc, time};
+use clap::Parser;
+use std::net::SocketAddr;
+use rkyv::{Archive, Deserialize, Serialize};
+use bytes::Bytes;
+
+pub mod admission;
+pub mod auth;
+pub mod backend;
+pub mod handshake;
 pub mod key_mapper;
-
+pub mod protocol;
+pub mod relay;
+pub mod stats;
+
+use crate::admission::{AccessList, AdmissionPolicy, HelloHistory, PeerPattern, PlayerSlots, PLAYER_SLOTS};
+use crate::auth::{Nonce, Secret, SessionKey, Ticket};
+use crate::backend::{Backend, BackendKind};
+use crate::handshake::{JoinOptions, PunchProgress, RetryPolicy};
 use crate::key_mapper::{KeyMapper, UserInput, ClientMessage};
+use crate::protocol::{ControllerKind, Handshake, HostMessage, MessageKind, PeerErrors, ProtocolError, Welcome, MAX_CLIENT_ID_LEN, MAX_PAYLOAD, RELAY_KEEPALIVE_INTERVAL};
+use crate::relay::relay;
+use crate::stats::LinkStats;
 
-const MAX_PAYLOAD: usize = 65507;
 const DEFAULT_SERVER_PORT: u16 = 45681;
 const DEFAULT_CLIENT_PORT: u16 = 45682;
+const MAX_HANDSHAKE_BACKOFF: Duration = Duration::from_secs(4);
+const DISCONNECT_REPEATS: usize = 3;
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -22,221 +38,631 @@ struct Args {
     relay: bool,
     #[arg(long)]
     server: bool,
-    relay_addr: Option<String>,
+    /// Run the server without a relay and accept any client that connects directly
+    #[arg(long)]
+    listen: bool,
+    /// Connect the client straight to a host instead of going through a relay
+    #[arg(long, value_name = "HOST:PORT")]
+    direct: Option<SocketAddr>,
+    /// Virtual controller backend used by the server
+    #[arg(long, value_enum, default_value_t = BackendKind::default())]
+    backend: BackendKind,
+    /// Room code shared by a host and its clients on the relay
+    #[arg(long, default_value = "default")]
+    room: String,
+    /// Secret shared by a host and its clients, used to authenticate every client packet
+    #[arg(long, conflicts_with = "secret_file")]
+    secret: Option<String>,
+    /// File whose first line is the shared secret
+    #[arg(long)]
+    secret_file: Option<PathBuf>,
+    /// Let the server run without a shared secret, so anyone who can reach it can join
+    #[arg(long, conflicts_with_all = ["secret", "secret_file"])]
+    no_secret: bool,
+    /// Encrypt the client's session with a key only it and the host know
+    #[arg(long)]
+    encrypt: bool,
+    /// Refuse clients that don't encrypt their session
+    #[arg(long)]
+    require_encryption: bool,
+    /// Most controllers the server plugs in at once, clients beyond that are turned away
+    #[arg(long, default_value_t = PLAYER_SLOTS, value_parser = clap::value_parser!(u8).range(1..=16))]
+    max_controllers: u8,
+    /// Client ID, IP address or IP:PORT allowed to join. When set, anyone else is refused
+    #[arg(long, value_name = "CLIENT")]
+    allow: Vec<PeerPattern>,
+    /// Client ID, IP address or IP:PORT that is never allowed to join
+    #[arg(long, value_name = "CLIENT")]
+    deny: Vec<PeerPattern>,
+    /// Controller the client asks the host to plug in for it
+    #[arg(long, value_enum, default_value_t = ControllerKind::default())]
+    controller: ControllerKind,
+    /// ID the host recognizes the client by, so it keeps its player slot when it
+    /// reconnects. Generated and saved next to the config if not set
+    #[arg(long)]
+    client_id: Option<String>,
+    /// How often the client repeats its current input while nothing changes, so
+    /// a lost datagram is corrected quickly. 0 only sends changes
+    #[arg(long, default_value_t = 50)]
+    resend_interval_ms: u64,
+    /// How often the client tells the host it is still there
+    #[arg(long, default_value_t = 500)]
+    heartbeat_interval_ms: u64,
+    /// How long the client waits without hearing from the host before reconnecting
+    #[arg(long, default_value_t = 3000)]
+    host_timeout_ms: u64,
+    /// How often the client prints round trip time, jitter and loss of its link to the host. 0 turns it off
+    #[arg(long, default_value_t = 10)]
+    stats_interval_s: u64,
+    /// How long the server waits without hearing from a client before releasing
+    /// everything on its controller
+    #[arg(long, default_value_t = 1000)]
+    neutral_timeout_ms: u64,
+    /// How long the server waits without hearing from a client before unplugging its controller
+    #[arg(long, default_value_t = 10000)]
+    unplug_timeout_ms: u64,
+    /// How many times each handshake step is attempted before giving up
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    handshake_attempts: u32,
+    /// Initial wait for a handshake answer in milliseconds, doubled after every attempt
+    #[arg(long, default_value_t = 250, value_parser = clap::value_parser!(u64).range(1..))]
+    handshake_backoff_ms: u64,
+    relay_addr: Option<SocketAddr>,
+    /// Address to bind to
+    #[arg(long)]
     addr: Option<String>,
+    /// Port to bind to
+    #[arg(long)]
     port: Option<u16>,
-    config: Option<Path>
+    /// Key binding config used by the client
+    #[arg(long)]
+    config: Option<PathBuf>
 }
 
-#[derive(Archive, Deserialize, Serialize, Debug)]
-struct Handshake {
-    role: u8,
-    addr: SocketAddr,
+/// How a client finds its host.
+#[derive(Clone, Copy)]
+enum Rendezvous {
+    Relay(SocketAddr),
+    Direct(SocketAddr),
 }
 
 #[derive(Archive, Deserialize, Serialize, Debug)]
+#[archive(check_bytes)]
 struct Keys {
     keys: Vec<u8>
 }
 
+/// How long a client's controller keeps its state after the client goes quiet.
 #[derive(Clone, Copy)]
-#[repr(u8)]
-enum MessageType {
-    Host = 0,
-    Client = 1,
-    Other,
+struct ControllerTimeouts {
+    /// Until the controller is reset to neutral, so held inputs don't run away
+    neutral: Duration,
+    /// Until the controller is unplugged
+    unplug: Duration,
 }
 
-impl From<u8> for MessageType {
-    fn from(num: u8) -> Self {
-        match num {
-            0 => MessageType::Host,
-            1 => MessageType::Client,
-            _ => MessageType::Other
-        }
-    }
+/// How often the client sends without new input, and how long it waits to
+/// hear back from the host.
+struct ClientTiming {
+    heartbeat: Duration,
+    /// Repeating the current input, if enabled
+    resend: Option<Duration>,
+    /// Silence after which the host is considered lost and the client reconnects
+    host_timeout: Duration,
+    /// Printing the link statistics, if enabled
+    stats: Option<Duration>,
 }
 
-async fn relay(addr: Option<String>, port: u16) -> Result<()> {
-    let conn = UdpSocket::bind(format!("{}:{port}", addr.unwrap_or("0.0.0.0".to_string()))).await?;
-    
-    let mut host: Option<SocketAddr> = None;
-    let mut clients: Vec<SocketAddr> = Vec::new();
-
-    loop {
-        let mut buffer = [0; 1];
-        let (_, addr) = conn.recv_from(&mut buffer).await?;
-        let role = u8::from_be_bytes(buffer);
-        match MessageType::from(role) {
-            MessageType::Host => {
-                // If there's already a host, do nothing
-                if host.is_none() {
-                    let host_addr = addr;
-                    // Send host info to each client
-                    for client in clients.iter() {
-                        let host_info = &rkyv::to_bytes::<_, MAX_PAYLOAD>(&host_addr).expect("Failed to serialize host info");
-                        conn.send_to(host_info, client).await?;
-                    }
-                    
-                    // Send list of clients to host address to punch through to
-                    let clients_msg = &rkyv::to_bytes::<_, MAX_PAYLOAD>(&clients).expect("Failed to serialize list of clients");
-                    conn.send_to(clients_msg, addr);
-                    host = Some(addr);
-                }
-            },
-            MessageType::Client => {
-                // Exchange host and client information
-                if let Some(host_addr) = host {
-                    let host_info = &rkyv::to_bytes::<_, MAX_PAYLOAD>(&host_addr).expect("Failed to serialize host info");
-                    let client_info = &rkyv::to_bytes::<_, MAX_PAYLOAD>(&addr).expect("Failed to serialize client info");
-                    conn.send_to(host_info, addr).await?;
-                    conn.send_to(client_info, host_addr).await?;
-                }
-                clients.push(addr);
-            }
-            _ => {}
-        }
+/// What the server loop hands to a client's controller task.
+enum ControllerCommand {
+    Message(ClientMessage),
+    /// The client started a new session, so its sequence numbers start over
+    NewSession,
+}
 
-    } 
+/// A client the server admitted, and what it needs to authenticate its datagrams.
+struct ClientSession {
+    channel: mpsc::Sender<ControllerCommand>,
+    key: SessionKey,
+    /// Who the client says it is, which decides its player slot
+    client_id: String,
+    slot: u8,
+    controller: ControllerKind,
+    /// Nonce of the handshake that started the session, to recognize retransmits
+    hello: Nonce,
+    /// Encoded accept, resent when the client retransmits its handshake
+    accept: Bytes,
 }
 
-async fn setup_client(client: &SocketAddr, vigem: Arc<Client>) -> mpsc::Sender<ClientMessage> {
-    let (tx, mut rx) = mpsc::channel::<ClientMessage>(1000);
-    let mut controller = vigem_client::Xbox360Wired::new(vigem, vigem_client::TargetId::XBOX360_WIRED);
-    controller.plugin().expect("Failed to plugin controller");
-    controller.wait_ready().expect("Failed to wait ready controller");
+async fn setup_client(client: &SocketAddr, backend: &Backend, slot: u8, kind: ControllerKind, timeouts: ControllerTimeouts) -> Result<mpsc::Sender<ControllerCommand>> {
+    let (tx, mut rx) = mpsc::channel::<ControllerCommand>(1000);
+    let mut controller = backend.create(slot, kind);
+    controller.plugin()?;
+    println!("Plugged in {} controller {} for {}", kind, slot, client);
+    let client = *client;
 
     tokio::spawn(async move {
+        // Sequence number of the input the controller currently shows
+        let mut applied: Option<u64> = None;
+        // Whether the controller was reset after the client went quiet
+        let mut neutral = false;
+        let mut last_heard = time::Instant::now();
         loop {
-            match time::timeout(Duration::from_secs(10), rx.recv()).await {
-                Ok(Some(message)) => {
+            let timeout = if neutral { timeouts.unplug } else { timeouts.neutral };
+            match time::timeout_at(last_heard + timeout, rx.recv()).await {
+                Ok(Some(ControllerCommand::Message(message))) => {
+                    last_heard = time::Instant::now();
                     match message {
-                        ClientMessage::Input(input) => {
-                            let gamepad = vigem_client::Xgamepad {
-                                thumb_lx: input.lx,
-                                thumb_ly: input.ly,
-                                thumb_rx: input.rx,
-                                thumb_ry: input.ry,
-                                left_trigger: input.ltrigger,
-                                right_trigger: input.rtrigger,
-                                buttons: input.buttons
-                            };
-                        
-                            if let Err(e) = controller.update(&gamepad) {
+                        ClientMessage::Input { sequence, input } => {
+                            match applied {
+                                // Resent to cover for packet loss, but this one made it. After a reset it
+                                // restores what the client is still holding.
+                                Some(applied) if sequence == applied && !neutral => continue,
+                                // A reordered datagram must not undo newer input, like releasing a button
+                                Some(applied) if sequence < applied => {
+                                    eprintln!("Dropping stale input {} from {}, already applied {}", sequence, client, applied);
+                                    continue;
+                                }
+                                _ => {}
+                            }
+                            applied = Some(sequence);
+                            neutral = false;
+                            if let Err(e) = controller.update(&input) {
                                 eprintln!("Error updating controller: {:?}", e);
                             }
                         }
-                        ClientMessage::Hearbeat => {},
+                        ClientMessage::Hearbeat { .. } => {},
+                        ClientMessage::Disconnect => {
+                            println!("Client {} disconnected", client);
+                            break;
+                        }
                     }
                 },
-                _ => {
-                    // Close controller
-                    controller.unplug();
-                    return;
+                Ok(Some(ControllerCommand::NewSession)) => applied = None,
+                Ok(None) => break,
+                Err(_) if !neutral => {
+                    println!("Client {} went quiet, releasing its controller", client);
+                    neutral = true;
+                    if let Err(e) = controller.update(&UserInput::default()) {
+                        eprintln!("Error resetting controller: {:?}", e);
+                    }
+                }
+                Err(_) => {
+                    println!("Client {} timed out", client);
+                    break;
                 }
             }
         }
+
+        // Release everything first so nothing stays held if the unplug goes wrong
+        if let Err(e) = controller.update(&UserInput::default()) {
+            eprintln!("Error resetting controller: {:?}", e);
+        }
+        if let Err(e) = controller.unplug() {
+            eprintln!("Error unplugging controller: {:?}", e);
+        }
     });
 
-    return tx;
+    Ok(tx)
+}
+
+/// Tells a client that introduced itself why it can't join.
+async fn reject(conn: &UdpSocket, client: SocketAddr, reason: String) {
+    eprintln!("Rejected {}: {}", client, reason);
+    protocol::send_datagram(conn, &protocol::encode(MessageKind::Reject, &reason), client).await;
 }
 
-async fn server(relay_addr: SocketAddr, server_addr: Option<String>, server_port: Option<u16>) -> Result<()> {
+async fn server(relay_addr: Option<SocketAddr>, admission: AdmissionPolicy, server_addr: Option<String>, server_port: Option<u16>, backend: Backend, timeouts: ControllerTimeouts, policy: RetryPolicy) -> Result<()> {
     let conn = Arc::new(UdpSocket::bind(format!("{}:{}", server_addr.unwrap_or("0.0.0.0".to_string()), server_port.unwrap_or(DEFAULT_SERVER_PORT))).await?);
-    let mut client_channels: HashMap<SocketAddr, mpsc::Sender<ClientMessage>> = HashMap::new();
+    let mut sessions: HashMap<SocketAddr, ClientSession> = HashMap::new();
+    // Clients we are still punching through to
+    let mut punches: HashMap<SocketAddr, PunchProgress> = HashMap::new();
+    let mut slots = PlayerSlots::new(admission.max_controllers());
+    let mut history = HelloHistory::new();
+    let mut errors = PeerErrors::default();
 
     // UDP Punchthrough
-    let role: [u8; 1] = [0];
-    conn.send_to(&role, relay_addr);
-
-    let mut clients_buffer = [0; MAX_PAYLOAD];
-    let bytes_recv: usize;
-    let recv_addr: SocketAddr;
-    match time::timeout(Duration::from_secs(5), conn.recv_from(&mut clients_buffer)).await {
-        Ok((bytes_recv, recv_addr)) => {
-            bytes_recv = bytes_recv;
-            recv_addr = recv_addr; 
+    let clients = match relay_addr {
+        Some(relay_addr) => handshake::register_host(&conn, relay_addr, admission.room(), &policy, &mut errors).await?,
+        None => {
+            println!("Listening for direct connections on {}", conn.local_addr()?);
+            Vec::new()
         }
-        Err(_) => { panic!("Failed to connect to the relay server") }
-    }
-    /*
-    if let Err(_) = time::timeout(Duration::from_secs(5), conn.recv_from(&mut clients_buffer)).await {
-        panic!("Failed to connect to the relay server");
-    }
-    */
-    let vigem: Arc<Client> = Arc::new(vigem_client::Client::connect().expect("Can't retrieve vigem client: 159"));
-
-    let archived_clients = unsafe { rkyv::archived_root::<Vec<SocketAddr>>(&clients_buffer[..bytes_recv]) };
-    let clients: Vec<SocketAddr> = archived_clients.deserialize(&mut rkyv::Infallible).unwrap();
+    };
+    // Open our side of the NAT towards every client the relay knows about.
+    // They get a controller once they introduce themselves.
     for client in clients {
-        let tx = setup_client(&client, vigem.clone()).await;
-        client_channels.insert(client, tx);
+        punches.insert(client, PunchProgress::new(client));
     }
 
 
+    let registration = handshake::host_handshake(admission.room());
+    let mut keepalive = time::interval(RELAY_KEEPALIVE_INTERVAL);
+    let mut probe = time::interval(policy.backoff);
+    // Ticks missed while nobody needed probing must not all fire at once and
+    // use up the next client's attempts
+    probe.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    let mut sweep = time::interval(SESSION_SWEEP_INTERVAL);
+    keepalive.tick().await;
+
     loop {
         let mut buffer = [0; MAX_PAYLOAD];
-        let (bytes_recv, addr) = conn.recv_from(&mut buffer).await?;
-
-        if let Some(channel) = client_channels.get(&addr) {
-            // Message from existing client
-            let archived_message = unsafe { rkyv::archived_root::<ClientMessage>(&buffer[..bytes_recv]) };
-            let client_message: ClientMessage = archived_message.deserialize(&mut rkyv::Infallible).unwrap();
-            channel.send(client_message);
-        } else if addr == relay_addr {
-            // Message from relay server for new clients
-            let archived_clients = unsafe { rkyv::archived_root::<Vec<SocketAddr>>(&buffer[..bytes_recv]) };
-            let clients: Vec<SocketAddr> = archived_clients.deserialize(&mut rkyv::Infallible).unwrap();
-            for client in clients {
-                let tx = setup_client(&client, vigem.clone()).await;
-                client_channels.insert(client, tx);
+        let (bytes_recv, addr) = tokio::select! {
+            result = protocol::recv_datagram(&conn, &mut buffer) => result?,
+            _ = keepalive.tick(), if relay_addr.is_some() => {
+                // Stay registered with the relay so new clients can still find us,
+                // registering again if it restarted or expired us meanwhile
+                protocol::send_datagram(&conn, &registration, relay_addr.unwrap()).await;
+                continue;
+            }
+            _ = sweep.tick() => {
+                // Forget clients whose controller was unplugged after they went quiet,
+                // so clients that come back from new ports don't pile up
+                sessions.retain(|client, session| {
+                    let live = !session.channel.is_closed();
+                    if !live {
+                        punches.remove(client);
+                    }
+                    live
+                });
+                continue;
+            }
+            _ = probe.tick(), if !punches.is_empty() => {
+                // Keep probing clients whose path isn't confirmed both ways yet
+                let punch = protocol::encode_empty(MessageKind::Punch);
+                for (client, progress) in punches.iter_mut() {
+                    protocol::send_datagram(&conn, &punch, *client).await;
+                    progress.attempts += 1;
+                }
+                punches.retain(|client, progress| {
+                    let exhausted = progress.attempts >= policy.attempts;
+                    if exhausted {
+                        eprintln!("Giving up punching through to {}: still {:?} after {} attempts", client, progress.state(), progress.attempts);
+                    }
+                    !exhausted
+                });
+                continue;
+            }
+        };
+        let packet = match protocol::decode(&buffer[..bytes_recv]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                errors.record(addr, &e);
+                protocol::answer_version_mismatch(&conn, &buffer[..bytes_recv], addr, &e).await;
+                continue;
+            }
+        };
+
+        match packet.kind {
+            MessageKind::Client => {
+                if let Some(session) = sessions.get(&addr) {
+                    // Message from existing client, dropped unless it was sealed with its session key
+                    match packet.sealed_message::<ClientMessage>(&session.key) {
+                        Ok(client_message) => {
+                            let disconnect = matches!(client_message, ClientMessage::Disconnect);
+                            if let ClientMessage::Hearbeat { ping, sent_at } = client_message {
+                                protocol::send_datagram(&conn, &protocol::encode_sealed(MessageKind::Host, &HostMessage::Pong { ping, sent_at }, &session.key), addr).await;
+                            }
+                            let unplugged = session.channel.send(ControllerCommand::Message(client_message)).await.is_err();
+                            // The task unplugs the controller, or already has after the client went quiet for too long.
+                            // Forget the client so it has to introduce itself again.
+                            if disconnect || unplugged {
+                                sessions.remove(&addr);
+                                punches.remove(&addr);
+                            }
+                        }
+                        Err(e) => {
+                            errors.record(addr, &e);
+                        }
+                    }
+                } else {
+                    // Message from client we haven't connected to yet, it has to introduce itself first
+                    errors.record(addr, &ProtocolError::UnknownPeer);
+                }
+            }
+            MessageKind::ClientList if Some(addr) == relay_addr => {
+                // Message from relay server for new clients
+                let clients = match packet.message::<Vec<SocketAddr>>() {
+                    Ok(clients) => clients,
+                    Err(e) => {
+                        errors.record(addr, &e);
+                        continue;
+                    }
+                };
+                for client in clients {
+                    if sessions.contains_key(&client) {
+                        continue;
+                    }
+                    punches.entry(client).or_insert_with(|| PunchProgress::new(client));
+                }
+            }
+            MessageKind::Punch if sessions.contains_key(&addr) || punches.contains_key(&addr) => {
+                protocol::send_datagram(&conn, &protocol::encode_empty(MessageKind::PunchAck), addr).await;
+                if let Some(progress) = punches.get_mut(&addr) {
+                    progress.record_probe();
+                    if progress.is_established() {
+                        punches.remove(&addr);
+                    }
+                }
+            }
+            MessageKind::PunchAck if sessions.contains_key(&addr) || punches.contains_key(&addr) => {
+                if let Some(progress) = punches.get_mut(&addr) {
+                    progress.record_ack();
+                    if progress.is_established() {
+                        punches.remove(&addr);
+                    }
+                }
+            }
+            // Ack for a registration that already went through, or for a keepalive
+            MessageKind::RelayAck if Some(addr) == relay_addr => {}
+            // Another host took over the room while the relay had lost track of us
+            MessageKind::RelayReject if Some(addr) == relay_addr => handshake::check_rejected(&packet)?,
+            // A client introducing itself, whether the relay told us about it or not
+            MessageKind::Handshake => {
+                let handshake = match packet.message::<Handshake>() {
+                    Ok(handshake) => handshake,
+                    Err(e) => {
+                        errors.record(addr, &e);
+                        continue;
+                    }
+                };
+                let hello = match admission.check(addr, &handshake) {
+                    Ok(hello) => hello,
+                    // A handshake that fails the check must not be able to kick out a client that already joined
+                    Err(_) if sessions.contains_key(&addr) => {
+                        errors.record(addr, &ProtocolError::Unauthenticated);
+                        continue;
+                    }
+                    Err(reason) => {
+                        reject(&conn, addr, reason).await;
+                        punches.remove(&addr);
+                        continue;
+                    }
+                };
+                // A client whose controller was unplugged in the meantime gets a new one
+                if sessions.get(&addr).is_some_and(|session| session.channel.is_closed()) {
+                    sessions.remove(&addr);
+                }
+                // Retransmitted handshake from a client whose accept got lost
+                if let Some(session) = sessions.get(&addr).filter(|session| session.hello == hello.nonce) {
+                    protocol::send_datagram(&conn, &session.accept, addr).await;
+                    continue;
+                }
+                // Anything else carrying a nonce we accepted before is a replay
+                if history.seen(&hello) {
+                    errors.record(addr, &ProtocolError::Replayed);
+                    continue;
+                }
+                // A resume proof made with the key of an earlier session shows the sender
+                // is that session's client, so it may take its controller to a new address.
+                // One that was unplugged meanwhile can't be taken along, the client gets a new one.
+                let resumed = handshake.resume.and_then(|resume| {
+                    sessions
+                        .iter()
+                        .filter(|(_, session)| !session.channel.is_closed())
+                        .find(|(_, session)| session.hello == resume.previous && session.client_id == handshake.client_id)
+                        .filter(|(_, session)| session.key.verify_resume(&resume, &hello))
+                        .map(|(previous, _)| *previous)
+                });
+                match resumed {
+                    Some(previous) if previous != addr => {
+                        println!("Client {} moved to {}", previous, addr);
+                        let session = sessions.remove(&previous).expect("Session was just found");
+                        sessions.insert(addr, session);
+                        punches.remove(&previous);
+                    }
+                    Some(_) => {}
+                    // Without one, a client only gets back the controller at its own address
+                    // under its own ID, as after a restart, so nobody can take over another's
+                    None if sessions.get(&addr).is_some_and(|session| session.client_id != handshake.client_id) => {
+                        reject(&conn, addr, format!("another client is connected from {}", addr)).await;
+                        continue;
+                    }
+                    None => {}
+                }
+                // A client that wants a different controller gets a new one in its slot
+                if sessions.get(&addr).is_some_and(|session| session.controller != handshake.controller) {
+                    sessions.remove(&addr);
+                }
+                let slot = match sessions.get(&addr) {
+                    Some(session) => Ok(session.slot),
+                    None => slots.assign(&handshake.client_id, |slot| {
+                        sessions.values().any(|session| session.slot == slot && !session.channel.is_closed())
+                    }),
+                };
+                let (slot, accept, key) = match slot.and_then(|slot| Ok((slot, admission.accept(&hello, slot)?))) {
+                    Ok((slot, (accept, key))) => (slot, accept, key),
+                    Err(reason) => {
+                        reject(&conn, addr, reason).await;
+                        punches.remove(&addr);
+                        continue;
+                    }
+                };
+                // Plug in a new client's controller before accepting it, so it hears if that fails
+                let channel = match sessions.contains_key(&addr) {
+                    true => None,
+                    false => match setup_client(&addr, &backend, slot, handshake.controller, timeouts).await {
+                        Ok(channel) => Some(channel),
+                        Err(e) => {
+                            eprintln!("Failed to plug in a controller for {}: {:#}", addr, e);
+                            reject(&conn, addr, format!("the host could not plug in a {} controller", handshake.controller)).await;
+                            punches.remove(&addr);
+                            continue;
+                        }
+                    },
+                };
+                let accept = protocol::encode(MessageKind::Accept, &Welcome { proof: accept, slot });
+                protocol::send_datagram(&conn, &accept, addr).await;
+                history.remember(&hello);
+                let encryption = if key.is_encrypted() { "encrypted" } else { "unencrypted" };
+                match channel {
+                    // The client restarted or reconnected, keep its controller but start a new session
+                    None => {
+                        let session = sessions.get_mut(&addr).expect("Client has a session");
+                        println!("Player {} ({}) started a new {} session from {}", slot, handshake.client_id, encryption, addr);
+                        session.key = key;
+                        session.hello = hello.nonce;
+                        session.accept = accept;
+                        let _ = session.channel.send(ControllerCommand::NewSession).await;
+                    }
+                    Some(channel) => {
+                        println!("Player {} ({}) joined with an {} session from {}", slot, handshake.client_id, encryption, addr);
+                        let controller = handshake.controller;
+                        sessions.insert(addr, ClientSession { channel, key, client_id: handshake.client_id, slot, controller, hello: hello.nonce, accept });
+                    }
+                }
+                punches.entry(addr).or_insert_with(|| PunchProgress::new(addr));
+            }
+            kind => {
+                errors.record(addr, &ProtocolError::UnexpectedKind(kind));
             }
-        } else {
-            // Message from client we haven't connected to yet
         }
     }
 }
 
-async fn client(relay_addr: SocketAddr, client_addr: Option<String>, client_port: Option<u16>, key_mapper: KeyMapper) -> Result<()> {
+/// A client's link to its host.
+struct Connection {
+    host: SocketAddr,
+    relay_addr: Option<SocketAddr>,
+    ticket: Ticket,
+}
+
+/// Finds the host, introduces us and punches through to it. The session in
+/// `previous` is resumed if the host still has it.
+async fn connect(conn: &UdpSocket, rendezvous: Rendezvous, options: &JoinOptions, previous: Option<&Ticket>, policy: &RetryPolicy, errors: &mut PeerErrors) -> Result<Connection> {
+    let (host, relay_addr) = match rendezvous {
+        Rendezvous::Relay(relay_addr) => (handshake::register_client(conn, relay_addr, &options.room, policy, errors).await?, Some(relay_addr)),
+        Rendezvous::Direct(host) => (host, None),
+    };
+    let mut progress = PunchProgress::new(host);
+    let ticket = handshake::hello(conn, host, options, previous, policy, &mut progress, errors).await?;
+    handshake::punch(conn, host, policy, progress, errors).await?;
+    println!("Connected to host {}", host);
+    Ok(Connection { host, relay_addr, ticket })
+}
+
+async fn client(rendezvous: Rendezvous, options: JoinOptions, client_addr: Option<String>, client_port: Option<u16>, mut key_mapper: KeyMapper, timing: ClientTiming, policy: RetryPolicy) -> Result<()> {
     let conn = UdpSocket::bind(format!("{}:{}", client_addr.unwrap_or("0.0.0.0".to_string()), client_port.unwrap_or(DEFAULT_CLIENT_PORT))).await?;
+    let mut errors = PeerErrors::default();
+    // Keeps counting across sessions, so the host never takes new input for stale input
+    let mut sequence: u64 = 0;
+    let mut previous: Option<Ticket> = None;
+
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
 
-    // UDP Punchthrough 
-    let role: [u8; 1] = [1];
-    conn.send_to(&role, relay_addr);
-
-    let mut host_buffer = [0; MAX_PAYLOAD];
-    let bytes_recv: usize;
-    let recv_addr: SocketAddr;
-    match time::timeout(Duration::from_secs(5), conn.recv_from(&mut host_buffer)).await {
-        Ok((bytes_recv, recv_addr)) => {
-            bytes_recv = bytes_recv;
-            recv_addr = recv_addr;
+    loop {
+        // UDP Punchthrough
+        let connected = tokio::select! {
+            result = &mut shutdown => return Ok(result?),
+            connected = connect(&conn, rendezvous, &options, previous.as_ref(), &policy, &mut errors) => connected,
+        };
+        let connection = match connected {
+            Ok(connection) => connection,
+            // Nothing was working yet, so it needs fixing before trying again makes sense
+            Err(e) if previous.is_none() => return Err(e),
+            Err(e) => {
+                eprintln!("Failed to reconnect: {:#}", e);
+                tokio::select! {
+                    result = &mut shutdown => return Ok(result?),
+                    _ = time::sleep(RECONNECT_DELAY) => continue,
+                }
+            }
+        };
+        previous = Some(connection.ticket);
+
+        tokio::select! {
+            result = &mut shutdown => {
+                result?;
+                // Let the host unplug our controller right away instead of waiting for it to time out.
+                // Sent a few times since nothing answers it.
+                let disconnect = protocol::encode_sealed(MessageKind::Client, &ClientMessage::Disconnect, &connection.ticket.key);
+                for _ in 0..DISCONNECT_REPEATS {
+                    conn.send_to(&disconnect, connection.host).await?;
+                }
+                println!("Disconnected from host {}", connection.host);
+                return Ok(());
+            }
+            result = play(&conn, &connection, &mut key_mapper, &timing, &mut sequence, &mut errors) => {
+                if let Err(e) = result {
+                    eprintln!("Lost connection to host {}: {:#}", connection.host, e);
+                }
+                println!("Reconnecting to host {}", connection.host);
+            }
         }
-        Err(_) => { panic!("Failed to connect to the relay server"); }
     }
-    /* 
-    if let Err(_) = time::timeout(Duration::from_secs(5), conn.recv_from(&mut host_buffer)).await {
-        panic!("Failed to connect to the relay server");
-    }
-    */
+}
 
-    let archived_host = unsafe { rkyv::archived_root::<SocketAddr>(&host_buffer[..bytes_recv]) };
-    let host: SocketAddr = archived_host.deserialize(&mut rkyv::Infallible).unwrap();
+/// Sends input to the host until it stops answering.
+async fn play(conn: &UdpSocket, connection: &Connection, key_mapper: &mut KeyMapper, timing: &ClientTiming, sequence: &mut u64, errors: &mut PeerErrors) -> Result<()> {
+    let Connection { host, relay_addr, ticket: Ticket { key, .. } } = *connection;
+    let punch_ack = protocol::encode_empty(MessageKind::PunchAck);
 
     // TODO! Figure out the juggling between querying keys and the heartbeat timer
     let mut prev_input: Option<UserInput> = None;
+    let mut last_heard = time::Instant::now();
+    let mut link = LinkStats::new();
     let mut query = time::interval(Duration::from_millis(5));
-    let mut heartbeat = time::interval(Duration::from_secs(5));
+    let mut heartbeat = time::interval(timing.heartbeat);
+    let mut keepalive = time::interval(RELAY_KEEPALIVE_INTERVAL);
+    // Never polled without a resend interval, the fallback only has to be valid
+    let mut resend = time::interval(timing.resend.unwrap_or(RELAY_KEEPALIVE_INTERVAL));
+    let mut report = time::interval(timing.stats.unwrap_or(RELAY_KEEPALIVE_INTERVAL));
+    report.tick().await;
     heartbeat.tick().await;
+    keepalive.tick().await;
     query.tick().await;
 
+    let mut buffer = [0; MAX_PAYLOAD];
     loop {
         //https://stackoverflow.com/questions/68961504/non-blocking-recv-on-tokio-mpsc-receiver
         tokio::select! {
+            result = protocol::recv_datagram(conn, &mut buffer) => {
+                let (bytes_recv, addr) = result?;
+                if addr != host {
+                    continue;
+                }
+                match protocol::decode(&buffer[..bytes_recv]) {
+                    Ok(packet) if packet.kind == MessageKind::Host => match packet.sealed_message::<HostMessage>(&key) {
+                        Ok(HostMessage::Pong { ping, sent_at }) => {
+                            last_heard = time::Instant::now();
+                            link.pong(ping, sent_at);
+                        }
+                        Err(e) => errors.record(addr, &e),
+                    },
+                    // The host keeps probing until it has seen our answer
+                    Ok(packet) if packet.kind == MessageKind::Punch => {
+                        conn.send_to(&punch_ack, host).await?;
+                    }
+                    // Stragglers from the punch phase
+                    Ok(packet) if packet.kind == MessageKind::PunchAck => {}
+                    Ok(packet) => errors.record(addr, &ProtocolError::UnexpectedKind(packet.kind)),
+                    Err(e) => errors.record(addr, &e),
+                }
+            }
             _ = heartbeat.tick() => {
+                if last_heard.elapsed() > timing.host_timeout {
+                    eprintln!("Host {} stopped answering", host);
+                    return Ok(());
+                }
                 // Send heartbeat message
-                let hearbeat_bytes = &rkyv::to_bytes::<_, MAX_PAYLOAD>(&ClientMessage::Hearbeat).expect("Failed to serialize hearbeat message");
-                conn.send_to(hearbeat_bytes, host).await?;
+                let (ping, sent_at) = link.ping();
+                let hearbeat_bytes = protocol::encode_sealed(MessageKind::Client, &ClientMessage::Hearbeat { ping, sent_at }, &key);
+                conn.send_to(&hearbeat_bytes, host).await?;
+            }
+            _ = keepalive.tick(), if relay_addr.is_some() => {
+                // Stay registered with the relay in case the host has to re-register
+                conn.send_to(&protocol::encode_empty(MessageKind::Keepalive), relay_addr.unwrap()).await?;
+            }
+            _ = report.tick(), if timing.stats.is_some() => {
+                println!("Link to host {}: {}", host, link.report());
+            }
+            _ = resend.tick(), if timing.resend.is_some() => {
+                // Repeat the last input under its sequence number, in case that datagram got lost
+                if let Some(input) = prev_input {
+                    let input_bytes = protocol::encode_sealed(MessageKind::Client, &ClientMessage::Input { sequence: *sequence, input }, &key);
+                    conn.send_to(&input_bytes, host).await?;
+                }
             }
             _ = query.tick() => {
                 let input = key_mapper.get_input()?;
@@ -246,33 +672,93 @@ async fn client(relay_addr: SocketAddr, client_addr: Option<String>, client_port
                     }
                 }
                 prev_input = Some(input);
+                *sequence += 1;
 
-                let input_bytes = &rkyv::to_bytes::<_, MAX_PAYLOAD>(&ClientMessage::Input(input)).expect("Failed to serialize user input");
-                conn.send_to(input_bytes, host).await?;
+                let input_bytes = protocol::encode_sealed(MessageKind::Client, &ClientMessage::Input { sequence: *sequence, input }, &key);
+                conn.send_to(&input_bytes, host).await?;
+                // Only repeat it once it has been the current input for a while
+                resend.reset();
             }
         }
     }
+}
 
-    Ok(())
+/// Reads the client ID saved next to the controller config, or makes up a new
+/// one and saves it there, so the client is recognized across restarts.
+fn persistent_client_id(config: &Path) -> Result<String> {
+    let path = config.with_extension("id");
+    if let Ok(client_id) = fs::read_to_string(&path) {
+        return Ok(client_id.trim().to_string());
+    }
+    let client_id: String = rand::random::<[u8; 8]>().iter().map(|byte| format!("{:02x}", byte)).collect();
+    fs::write(&path, &client_id).with_context(|| format!("Failed to save the client ID to {}", path.display()))?;
+    println!("Saved new client ID {} to {}", client_id, path.display());
+    Ok(client_id)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();    
+    let policy = RetryPolicy {
+        attempts: args.handshake_attempts,
+        backoff: Duration::from_millis(args.handshake_backoff_ms),
+        max_backoff: MAX_HANDSHAKE_BACKOFF,
+    };
 
     if args.relay {
-        ensure!(args.port.is_some(), "The port needs to be set if running as a relay");
-        run_relay().await?;
-    } 
-
-    if args.server {
-        ensure!(args.relay_addr.is_some(), "A relay address needs to be provided");
-        server(args.relay_addr.unwrap(), args.addr, args.port).await?;
+        let Some(port) = args.port else {
+            bail!("The port needs to be set if running as a relay");
+        };
+        relay(args.addr, port).await?;
+    } else if args.server {
+        ensure!(args.listen || args.relay_addr.is_some(), "A relay address needs to be provided unless listening for direct connections");
+        let relay_addr = if args.listen { None } else { args.relay_addr };
+        let backend = Backend::connect(args.backend)?;
+        let secret = Secret::load(args.secret, args.secret_file.as_deref())?;
+        if secret.is_empty() {
+            ensure!(args.no_secret, "No session secret set; pass --secret or --secret-file, or --no-secret to let anyone who can reach the host join");
+            eprintln!("No session secret set, anyone who can reach the host can join");
+        }
+        let access = AccessList { allow: args.allow, deny: args.deny };
+        let admission = AdmissionPolicy::new(args.room, secret, args.require_encryption, access, args.max_controllers);
+        let timeouts = ControllerTimeouts {
+            neutral: Duration::from_millis(args.neutral_timeout_ms),
+            unplug: Duration::from_millis(args.unplug_timeout_ms),
+        };
+        ensure!(timeouts.neutral < timeouts.unplug, "The neutral timeout needs to be shorter than the unplug timeout");
+        server(relay_addr, admission, args.addr, args.port, backend, timeouts, policy).await?;
     } else {
-        ensure!(args.relay_addr.is_some(), "A relay address needs to be provided");
-        ensure!(args.config.is_some(), "A controller config path needs to be provided");
-        let keymap = KeyMapper::new(args.config.unwrap())?;
-        client(args.relay_addr.unwrap(), args.addr, args.port, keymap).await?;
+        let rendezvous = match (args.direct, args.relay_addr) {
+            (Some(host), _) => Rendezvous::Direct(host),
+            (None, Some(relay_addr)) => Rendezvous::Relay(relay_addr),
+            (None, None) => bail!("A relay address or a direct host address needs to be provided"),
+        };
+        let Some(config) = args.config else {
+            bail!("A controller config path needs to be provided");
+        };
+        let keymap = KeyMapper::new(&config)?;
+        let client_id = match args.client_id {
+            Some(client_id) => client_id,
+            None => persistent_client_id(&config)?,
+        };
+        ensure!(!client_id.is_empty() && client_id.len() <= MAX_CLIENT_ID_LEN, "The client ID needs to be between 1 and {} bytes long", MAX_CLIENT_ID_LEN);
+        let options = JoinOptions {
+            room: args.room,
+            client_id,
+            controller: args.controller,
+            // Whether a secret is needed is up to the host
+            secret: Secret::load(args.secret, args.secret_file.as_deref())?,
+            encrypt: args.encrypt,
+        };
+        ensure!(args.heartbeat_interval_ms > 0, "The heartbeat interval needs to be positive");
+        ensure!(args.host_timeout_ms > args.heartbeat_interval_ms, "The host timeout needs to be longer than the heartbeat interval");
+        let timing = ClientTiming {
+            heartbeat: Duration::from_millis(args.heartbeat_interval_ms),
+            resend: (args.resend_interval_ms > 0).then(|| Duration::from_millis(args.resend_interval_ms)),
+            host_timeout: Duration::from_millis(args.host_timeout_ms),
+            stats: (args.stats_interval_s > 0).then(|| Duration::from_secs(args.stats_interval_s)),
+        };
+        client(rendezvous, options, args.addr, args.port, keymap, timing, policy).await?;
     }
 
     Ok(())
@@ -283,3 +769,128 @@ async fn main() -> Result<()> {
 
     // else client, start recording keybinds and sending to host
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{ControllerEvent, RecordedEvent};
+
+    const CLIENT: SocketAddr = SocketAddr::V4(std::net::SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 40002));
+
+    fn timeouts(neutral_ms: u64, unplug_ms: u64) -> ControllerTimeouts {
+        ControllerTimeouts { neutral: Duration::from_millis(neutral_ms), unplug: Duration::from_millis(unplug_ms) }
+    }
+
+    /// Waits for the controller task to unplug and returns everything it did.
+    async fn recorded_until_unplug(backend: &Backend) -> Vec<ControllerEvent> {
+        let Backend::Recording(recorder) = backend else {
+            unreachable!("tests only use the recording backend");
+        };
+        for _ in 0..200 {
+            let events = recorder.events();
+            if events.last().map(|recorded| recorded.event) == Some(ControllerEvent::Unplug) {
+                return events.into_iter().map(|RecordedEvent { event, .. }| event).collect();
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("controller was never unplugged: {:?}", recorder.events());
+    }
+
+    fn input(sequence: u64, lx: i16) -> ControllerCommand {
+        ControllerCommand::Message(ClientMessage::Input { sequence, input: UserInput { lx, ..UserInput::default() } })
+    }
+
+    fn stick(lx: i16) -> ControllerEvent {
+        ControllerEvent::Update(UserInput { lx, ..UserInput::default() })
+    }
+
+    #[tokio::test]
+    async fn stale_inputs_are_dropped() {
+        let backend = Backend::connect(BackendKind::Recording).unwrap();
+        let tx = setup_client(&CLIENT, &backend, 1, ControllerKind::Xbox360, timeouts(10_000, 10_000)).await.unwrap();
+        tx.send(input(2, 200)).await.unwrap();
+        tx.send(input(1, 100)).await.unwrap();
+        tx.send(input(3, 300)).await.unwrap();
+        drop(tx);
+
+        assert_eq!(recorded_until_unplug(&backend).await, [
+            ControllerEvent::Plugin(ControllerKind::Xbox360),
+            stick(200),
+            stick(300),
+            stick(0),
+            ControllerEvent::Unplug,
+        ]);
+    }
+
+    #[tokio::test]
+    async fn repeated_inputs_are_skipped_until_the_controller_was_released() {
+        let backend = Backend::connect(BackendKind::Recording).unwrap();
+        let tx = setup_client(&CLIENT, &backend, 1, ControllerKind::Xbox360, timeouts(50, 10_000)).await.unwrap();
+        tx.send(input(1, 100)).await.unwrap();
+        tx.send(input(1, 100)).await.unwrap();
+        time::sleep(Duration::from_millis(150)).await;
+        // The client is still holding it, so the repeat restores it after the reset
+        tx.send(input(1, 100)).await.unwrap();
+        drop(tx);
+
+        assert_eq!(recorded_until_unplug(&backend).await, [
+            ControllerEvent::Plugin(ControllerKind::Xbox360),
+            stick(100),
+            stick(0),
+            stick(100),
+            stick(0),
+            ControllerEvent::Unplug,
+        ]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_unplugs_right_away() {
+        let backend = Backend::connect(BackendKind::Recording).unwrap();
+        let tx = setup_client(&CLIENT, &backend, 2, ControllerKind::DualShock4, timeouts(10_000, 10_000)).await.unwrap();
+        tx.send(input(0, 100)).await.unwrap();
+        tx.send(ControllerCommand::Message(ClientMessage::Disconnect)).await.unwrap();
+
+        assert_eq!(recorded_until_unplug(&backend).await, [
+            ControllerEvent::Plugin(ControllerKind::DualShock4),
+            stick(100),
+            stick(0),
+            ControllerEvent::Unplug,
+        ]);
+        // The task is gone, and the server notices through the closed channel
+        assert!(time::timeout(Duration::from_secs(1), tx.closed()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn quiet_client_is_released_then_unplugged() {
+        let backend = Backend::connect(BackendKind::Recording).unwrap();
+        let tx = setup_client(&CLIENT, &backend, 1, ControllerKind::Xbox360, timeouts(50, 50)).await.unwrap();
+        tx.send(input(0, 100)).await.unwrap();
+
+        assert_eq!(recorded_until_unplug(&backend).await, [
+            ControllerEvent::Plugin(ControllerKind::Xbox360),
+            stick(100),
+            stick(0),
+            stick(0),
+            ControllerEvent::Unplug,
+        ]);
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn new_session_starts_the_sequence_over() {
+        let backend = Backend::connect(BackendKind::Recording).unwrap();
+        let tx = setup_client(&CLIENT, &backend, 1, ControllerKind::Xbox360, timeouts(10_000, 10_000)).await.unwrap();
+        tx.send(input(5, 100)).await.unwrap();
+        tx.send(ControllerCommand::NewSession).await.unwrap();
+        tx.send(input(0, 200)).await.unwrap();
+        drop(tx);
+
+        assert_eq!(recorded_until_unplug(&backend).await, [
+            ControllerEvent::Plugin(ControllerKind::Xbox360),
+            stick(100),
+            stick(200),
+            stick(0),
+            ControllerEvent::Unplug,
+        ]);
+    }
+}
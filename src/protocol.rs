@@ -0,0 +1,426 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use rkyv::{
+    ser::serializers::AllocSerializer, validation::validators::DefaultValidator, AlignedVec, Archive, CheckBytes,
+    Deserialize, Infallible, Serialize
+};
+use clap::ValueEnum;
+use std::{collections::HashMap, fmt, io, net::SocketAddr, time::{Duration, Instant}};
+use tokio::net::UdpSocket;
+
+use crate::auth::{Proof, Resume, SessionKey};
+
+/// Every datagram starts with these bytes so stray traffic is rejected early.
+pub(crate) const MAGIC: [u8; 2] = *b"KT";
+/// Bumped whenever the header or any message layout changes.
+pub(crate) const PROTOCOL_VERSION: u8 = 1;
+/// Magic, version, kind and a 32-bit payload length.
+pub(crate) const HEADER_LEN: usize = 8;
+
+/// Largest payload a single UDP datagram can carry.
+pub(crate) const MAX_PAYLOAD: usize = 65507;
+/// Longest room code the relay will accept.
+pub(crate) const MAX_ROOM_LEN: usize = 64;
+/// Longest client ID a host will accept.
+pub(crate) const MAX_CLIENT_ID_LEN: usize = 64;
+/// How often hosts and clients remind the relay that they are still around.
+pub(crate) const RELAY_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long the relay keeps a peer registered without hearing from it.
+pub(crate) const RELAY_PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SCRATCH_SPACE: usize = 256;
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub(crate) enum MessageType {
+    Host = 0,
+    Client = 1,
+    Other,
+}
+
+impl From<u8> for MessageType {
+    fn from(num: u8) -> Self {
+        match num {
+            0 => MessageType::Host,
+            1 => MessageType::Client,
+            _ => MessageType::Other
+        }
+    }
+}
+
+/// Kind of virtual controller a client asks its host to plug in.
+#[derive(Archive, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[archive(check_bytes)]
+pub(crate) enum ControllerKind {
+    /// Wired Xbox 360 pad
+    #[default]
+    Xbox360,
+    /// Wired DualShock 4, for games that only show PlayStation prompts
+    #[value(name = "ds4")]
+    DualShock4,
+}
+
+impl fmt::Display for ControllerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControllerKind::Xbox360 => write!(f, "Xbox 360"),
+            ControllerKind::DualShock4 => write!(f, "DualShock 4"),
+        }
+    }
+}
+
+/// Registration sent to the relay, and by clients to introduce themselves to
+/// their host. Hosts and clients meet in the room named by `room`. Only the
+/// handshake to the host carries a `proof`, the relay doesn't know the secret.
+/// Clients also name themselves with a `client_id` that stays the same across
+/// restarts, and pick the `controller` they want. A reconnecting client asks
+/// to `resume` its previous session, which is what lets it keep its
+/// controller from a new address.
+#[derive(Archive, Deserialize, Serialize, Debug)]
+#[archive(check_bytes)]
+pub(crate) struct Handshake {
+    pub role: u8,
+    pub room: String,
+    pub client_id: String,
+    pub controller: ControllerKind,
+    pub proof: Option<Proof>,
+    pub resume: Option<Resume>,
+}
+
+/// A host admitting a client, telling it which player it is. Player slots
+/// count from 1.
+#[derive(Archive, Deserialize, Serialize, Debug)]
+#[archive(check_bytes)]
+pub(crate) struct Welcome {
+    pub proof: Proof,
+    pub slot: u8,
+}
+
+/// Sent by the host to a client, sealed with the session key.
+#[derive(Archive, Deserialize, Serialize, Debug)]
+#[archive(check_bytes)]
+pub(crate) enum HostMessage {
+    /// Answer to a heartbeat, echoing its fields so the client can tell the
+    /// host is still there and measure the round trip
+    Pong { ping: u64, sent_at: u64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum MessageKind {
+    /// Peer registering with the relay
+    Handshake = 0,
+    /// Relay telling a client where the host is
+    HostInfo = 1,
+    /// Relay telling the host which clients to expect
+    ClientList = 2,
+    /// Client input or heartbeat for the host, sealed with the session key
+    Client = 3,
+    /// Sent back to a peer whose packet used a different protocol version
+    VersionMismatch = 4,
+    /// Peer telling the relay it is still registered
+    Keepalive = 5,
+    /// Relay accepting a handshake
+    RelayAck = 6,
+    /// Relay refusing a handshake, with the reason as payload
+    RelayReject = 7,
+    /// Probe sent by either side to open a path through both NATs
+    Punch = 8,
+    /// Answer to a punch probe
+    PunchAck = 9,
+    /// Host admitting a client's handshake, with a `Welcome` as payload
+    Accept = 10,
+    /// Host refusing a client's handshake, with the reason as payload
+    Reject = 11,
+    /// Host status for a client, sealed with the session key
+    Host = 12,
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = ProtocolError;
+
+    fn try_from(num: u8) -> Result<Self, ProtocolError> {
+        match num {
+            0 => Ok(MessageKind::Handshake),
+            1 => Ok(MessageKind::HostInfo),
+            2 => Ok(MessageKind::ClientList),
+            3 => Ok(MessageKind::Client),
+            4 => Ok(MessageKind::VersionMismatch),
+            5 => Ok(MessageKind::Keepalive),
+            6 => Ok(MessageKind::RelayAck),
+            7 => Ok(MessageKind::RelayReject),
+            8 => Ok(MessageKind::Punch),
+            9 => Ok(MessageKind::PunchAck),
+            10 => Ok(MessageKind::Accept),
+            11 => Ok(MessageKind::Reject),
+            12 => Ok(MessageKind::Host),
+            _ => Err(ProtocolError::UnknownKind(num))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ProtocolError {
+    Truncated(usize),
+    BadMagic,
+    VersionMismatch(u8),
+    UnknownKind(u8),
+    LengthMismatch { expected: usize, actual: usize },
+    UnexpectedKind(MessageKind),
+    Malformed(String),
+    UnknownPeer,
+    Unauthenticated,
+    Replayed,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated(len) => write!(f, "datagram of {} bytes is too short for a header", len),
+            ProtocolError::BadMagic => write!(f, "datagram does not start with the protocol magic"),
+            ProtocolError::VersionMismatch(version) => write!(
+                f,
+                "peer speaks protocol version {} but this build speaks version {}; make sure everyone runs the same release",
+                version, PROTOCOL_VERSION
+            ),
+            ProtocolError::UnknownKind(kind) => write!(f, "unknown message kind {}", kind),
+            ProtocolError::LengthMismatch { expected, actual } => {
+                write!(f, "header announces {} payload bytes but {} arrived", expected, actual)
+            }
+            ProtocolError::UnexpectedKind(kind) => write!(f, "unexpected {:?} message", kind),
+            ProtocolError::Malformed(reason) => write!(f, "malformed payload: {}", reason),
+            ProtocolError::UnknownPeer => write!(f, "sender has not joined yet"),
+            ProtocolError::Unauthenticated => write!(f, "message authentication failed"),
+            ProtocolError::Replayed => write!(f, "handshake was already used"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// A decoded datagram. The payload is copied into an aligned buffer so it can
+/// be accessed as an rkyv archive.
+pub(crate) struct Packet {
+    pub kind: MessageKind,
+    pub payload: AlignedVec,
+}
+
+impl Packet {
+    /// Validates the payload as an archived `T` before deserializing it, so
+    /// malformed or hostile datagrams are rejected instead of being read blindly.
+    pub fn message<T: Archive>(&self) -> Result<T, ProtocolError>
+    where
+        T::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<T, Infallible>,
+    {
+        archived(&self.payload)
+    }
+
+    /// Like `message`, but first opens what `encode_sealed` protected.
+    pub fn sealed_message<T: Archive>(&self, key: &SessionKey) -> Result<T, ProtocolError>
+    where
+        T::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<T, Infallible>,
+    {
+        let opened = key.open(&sealed_header(self.kind), &self.payload).ok_or(ProtocolError::Unauthenticated)?;
+        let mut payload = AlignedVec::with_capacity(opened.len());
+        payload.extend_from_slice(&opened);
+        archived(&payload)
+    }
+}
+
+fn archived<T: Archive>(bytes: &[u8]) -> Result<T, ProtocolError>
+where
+    T::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<T, Infallible>,
+{
+    let archived = rkyv::check_archived_root::<T>(bytes)
+        .map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+    Ok(archived.deserialize(&mut Infallible).expect("Infallible deserialization failed"))
+}
+
+/// The header fields authenticated along with a sealed payload. The length is
+/// left out since it depends on the sealing.
+fn sealed_header(kind: MessageKind) -> [u8; 2] {
+    [PROTOCOL_VERSION, kind as u8]
+}
+
+fn encode_header(buf: &mut BytesMut, kind: MessageKind, len: usize) {
+    buf.put_slice(&MAGIC);
+    buf.put_u8(PROTOCOL_VERSION);
+    buf.put_u8(kind as u8);
+    buf.put_u32(len as u32);
+}
+
+/// Serializes `message` and frames it with a header.
+pub(crate) fn encode<T: Serialize<AllocSerializer<SCRATCH_SPACE>>>(kind: MessageKind, message: &T) -> Bytes {
+    let payload = rkyv::to_bytes::<_, SCRATCH_SPACE>(message).expect("Failed to serialize message");
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    encode_header(&mut buf, kind, payload.len());
+    buf.put_slice(&payload);
+    buf.freeze()
+}
+
+/// Serializes `message` and seals it with the session `key`, so only the peer
+/// holding the same key can check, and in encrypted sessions read, it.
+pub(crate) fn encode_sealed<T: Serialize<AllocSerializer<SCRATCH_SPACE>>>(kind: MessageKind, message: &T, key: &SessionKey) -> Bytes {
+    let payload = rkyv::to_bytes::<_, SCRATCH_SPACE>(message).expect("Failed to serialize message");
+    let sealed = key.seal(&sealed_header(kind), &payload);
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + sealed.len());
+    encode_header(&mut buf, kind, sealed.len());
+    buf.put_slice(&sealed);
+    buf.freeze()
+}
+
+/// A header without a payload.
+pub(crate) fn encode_empty(kind: MessageKind) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN);
+    encode_header(&mut buf, kind, 0);
+    buf.freeze()
+}
+
+/// Lets a peer whose `datagram` failed to decode with `error` know when that
+/// is because it runs another release. A mismatch notice is never answered, or
+/// two mismatched peers would bounce notices forever. This relies on every
+/// version keeping the kind right after magic and version.
+pub(crate) async fn answer_version_mismatch(conn: &UdpSocket, datagram: &[u8], peer: SocketAddr, error: &ProtocolError) {
+    if matches!(error, ProtocolError::VersionMismatch(_)) && datagram.get(3) != Some(&(MessageKind::VersionMismatch as u8)) {
+        send_datagram(conn, &encode_empty(MessageKind::VersionMismatch), peer).await;
+    }
+}
+
+/// Checks the header of a received datagram and splits off its payload.
+pub(crate) fn decode(mut datagram: &[u8]) -> Result<Packet, ProtocolError> {
+    if datagram.len() < HEADER_LEN {
+        return Err(ProtocolError::Truncated(datagram.len()));
+    }
+
+    let mut magic = [0; 2];
+    datagram.copy_to_slice(&mut magic);
+    if magic != MAGIC {
+        return Err(ProtocolError::BadMagic);
+    }
+
+    let version = datagram.get_u8();
+    if version != PROTOCOL_VERSION {
+        return Err(ProtocolError::VersionMismatch(version));
+    }
+
+    let kind = MessageKind::try_from(datagram.get_u8())?;
+    let expected = datagram.get_u32() as usize;
+    if expected != datagram.len() {
+        return Err(ProtocolError::LengthMismatch { expected, actual: datagram.len() });
+    }
+
+    let mut payload = AlignedVec::with_capacity(datagram.len());
+    payload.extend_from_slice(datagram);
+    Ok(Packet { kind, payload })
+}
+
+/// Most peers whose dropped datagrams are counted at once. Past that the peer
+/// heard from least recently is forgotten, so spoofed senders can't grow the map.
+const TRACKED_PEERS: usize = 1024;
+/// Shortest gap between two log lines about the same peer.
+const PEER_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+struct DroppedPackets {
+    count: u64,
+    last_seen: Instant,
+    last_logged: Option<Instant>,
+}
+
+/// Counts the datagrams dropped from each peer so a misbehaving peer can be
+/// spotted without one bad packet taking the whole process down. A peer that
+/// keeps sending garbage is logged at most once per `PEER_LOG_INTERVAL`.
+#[derive(Default)]
+pub(crate) struct PeerErrors {
+    peers: HashMap<SocketAddr, DroppedPackets>,
+}
+
+impl PeerErrors {
+    pub fn record(&mut self, peer: SocketAddr, error: &ProtocolError) {
+        let now = Instant::now();
+        if self.peers.len() >= TRACKED_PEERS && !self.peers.contains_key(&peer) {
+            let stalest = self.peers.iter().min_by_key(|(_, dropped)| dropped.last_seen).map(|(addr, _)| *addr);
+            if let Some(stalest) = stalest {
+                self.peers.remove(&stalest);
+            }
+        }
+
+        let dropped = self.peers.entry(peer).or_insert(DroppedPackets { count: 0, last_seen: now, last_logged: None });
+        dropped.count += 1;
+        dropped.last_seen = now;
+        if dropped.last_logged.is_none_or(|logged| now.duration_since(logged) >= PEER_LOG_INTERVAL) {
+            dropped.last_logged = Some(now);
+            eprintln!("Dropping packet from {} ({} dropped so far): {}", peer, dropped.count, error);
+        }
+    }
+}
+
+/// Receives the next datagram. Windows reports an ICMP port unreachable for an
+/// earlier send as a reset on the next receive, which says nothing about the
+/// socket itself, so those are skipped.
+pub(crate) async fn recv_datagram(conn: &UdpSocket, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    loop {
+        match conn.recv_from(buffer).await {
+            Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Sends `datagram` to `peer`, logging a failure instead of returning it so
+/// one unreachable peer can't stop a loop that serves everyone else.
+pub(crate) async fn send_datagram(conn: &UdpSocket, datagram: &[u8], peer: SocketAddr) {
+    if let Err(e) = conn.send_to(datagram, peer).await {
+        eprintln!("Failed to send to {}: {}", peer, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(version: u8, kind: u8, len: u32) -> Vec<u8> {
+        let mut datagram = MAGIC.to_vec();
+        datagram.extend_from_slice(&[version, kind]);
+        datagram.extend_from_slice(&len.to_be_bytes());
+        datagram
+    }
+
+    #[test]
+    fn decode_accepts_what_encode_produces() {
+        let packet = decode(&encode(MessageKind::Reject, &"room is full".to_string())).unwrap();
+        assert_eq!(packet.kind, MessageKind::Reject);
+        assert_eq!(packet.message::<String>().unwrap(), "room is full");
+        assert_eq!(decode(&encode_empty(MessageKind::Punch)).unwrap().kind, MessageKind::Punch);
+    }
+
+    #[test]
+    fn decode_rejects_broken_headers() {
+        assert_eq!(decode(&[]).err(), Some(ProtocolError::Truncated(0)));
+        assert_eq!(decode(&header(PROTOCOL_VERSION, 8, 0)[..7]).err(), Some(ProtocolError::Truncated(7)));
+
+        let mut bad_magic = header(PROTOCOL_VERSION, 8, 0);
+        bad_magic[0] = b'X';
+        assert_eq!(decode(&bad_magic).err(), Some(ProtocolError::BadMagic));
+
+        assert_eq!(decode(&header(PROTOCOL_VERSION + 1, 8, 0)).err(), Some(ProtocolError::VersionMismatch(PROTOCOL_VERSION + 1)));
+        assert_eq!(decode(&header(PROTOCOL_VERSION, 200, 0)).err(), Some(ProtocolError::UnknownKind(200)));
+
+        let mut longer = encode_empty(MessageKind::Punch).to_vec();
+        longer.push(0);
+        assert_eq!(decode(&longer).err(), Some(ProtocolError::LengthMismatch { expected: 0, actual: 1 }));
+        let shorter = encode(MessageKind::Reject, &"room is full".to_string());
+        let cut = shorter.len() - 1;
+        assert_eq!(decode(&shorter[..cut]).err(), Some(ProtocolError::LengthMismatch { expected: cut - HEADER_LEN + 1, actual: cut - HEADER_LEN }));
+    }
+
+    #[test]
+    fn malformed_archives_are_rejected() {
+        // A string whose length points far past the end of the payload
+        let mut datagram = header(PROTOCOL_VERSION, MessageKind::Reject as u8, 8);
+        datagram.extend_from_slice(&[0xff; 8]);
+        let packet = decode(&datagram).unwrap();
+        assert!(matches!(packet.message::<String>(), Err(ProtocolError::Malformed(_))));
+
+        let packet = decode(&encode_empty(MessageKind::ClientList)).unwrap();
+        assert!(matches!(packet.message::<Vec<SocketAddr>>(), Err(ProtocolError::Malformed(_))));
+    }
+}
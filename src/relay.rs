@@ -0,0 +1,180 @@
+use anyhow::Result;
+use std::{collections::HashMap, net::SocketAddr};
+use tokio::{net::UdpSocket, time::{self, Instant}};
+
+use crate::protocol::{
+    self, Handshake, MessageKind, MessageType, PeerErrors, ProtocolError, MAX_PAYLOAD, MAX_ROOM_LEN, RELAY_PEER_TIMEOUT
+};
+
+/// A single game session: one host and the clients waiting to join it, each
+/// with the last time the relay heard from them.
+#[derive(Default)]
+struct Room {
+    host: Option<(SocketAddr, Instant)>,
+    clients: HashMap<SocketAddr, Instant>,
+}
+
+impl Room {
+    /// The host, unless it has gone quiet for longer than `RELAY_PEER_TIMEOUT`.
+    fn live_host(&self, now: Instant) -> Option<SocketAddr> {
+        self.host
+            .filter(|(_, last_seen)| now.duration_since(*last_seen) <= RELAY_PEER_TIMEOUT)
+            .map(|(host, _)| host)
+    }
+
+    fn client_list(&self) -> Vec<SocketAddr> {
+        self.clients.keys().copied().collect()
+    }
+
+    /// Refreshes `addr` if it belongs to this room. Returns whether it did.
+    fn touch(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if let Some((host, last_seen)) = self.host.as_mut() {
+            if *host == addr {
+                *last_seen = now;
+                return true;
+            }
+        }
+        if let Some(last_seen) = self.clients.get_mut(&addr) {
+            *last_seen = now;
+            return true;
+        }
+        false
+    }
+
+    /// Drops every peer that hasn't been heard from within `RELAY_PEER_TIMEOUT`.
+    fn evict_stale(&mut self, name: &str, now: Instant) {
+        if let Some((host, last_seen)) = self.host {
+            if now.duration_since(last_seen) > RELAY_PEER_TIMEOUT {
+                println!("Host {} of room {:?} expired", host, name);
+                self.host = None;
+            }
+        }
+        self.clients.retain(|client, last_seen| {
+            let alive = now.duration_since(*last_seen) <= RELAY_PEER_TIMEOUT;
+            if !alive {
+                println!("Client {} of room {:?} expired", client, name);
+            }
+            alive
+        });
+    }
+
+    fn is_empty(&self) -> bool {
+        self.host.is_none() && self.clients.is_empty()
+    }
+}
+
+pub(crate) async fn relay(addr: Option<String>, port: u16) -> Result<()> {
+    let conn = UdpSocket::bind(format!("{}:{port}", addr.unwrap_or("0.0.0.0".to_string()))).await?;
+
+    let mut rooms: HashMap<String, Room> = HashMap::new();
+    let mut errors = PeerErrors::default();
+    let mut sweep = time::interval(RELAY_PEER_TIMEOUT / 2);
+
+    loop {
+        let mut buffer = [0; MAX_PAYLOAD];
+        let (bytes_recv, addr) = tokio::select! {
+            result = protocol::recv_datagram(&conn, &mut buffer) => result?,
+            _ = sweep.tick() => {
+                let now = Instant::now();
+                rooms.retain(|name, room| {
+                    room.evict_stale(name, now);
+                    !room.is_empty()
+                });
+                continue;
+            }
+        };
+
+        let packet = match protocol::decode(&buffer[..bytes_recv]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                errors.record(addr, &e);
+                protocol::answer_version_mismatch(&conn, &buffer[..bytes_recv], addr, &e).await;
+                continue;
+            }
+        };
+
+        let now = Instant::now();
+        let handshake = match packet.kind {
+            MessageKind::Keepalive => {
+                if !rooms.values_mut().any(|room| room.touch(addr, now)) {
+                    errors.record(addr, &ProtocolError::Malformed("keepalive from unregistered peer".to_string()));
+                }
+                continue;
+            }
+            MessageKind::Handshake => match packet.message::<Handshake>() {
+                Ok(handshake) if handshake.room.is_empty() || handshake.room.len() > MAX_ROOM_LEN => {
+                    let reason = format!("invalid room code {:?}", handshake.room);
+                    protocol::send_datagram(&conn, &protocol::encode(MessageKind::RelayReject, &reason), addr).await;
+                    errors.record(addr, &ProtocolError::Malformed(reason));
+                    continue;
+                }
+                Ok(handshake) => handshake,
+                Err(e) => {
+                    errors.record(addr, &e);
+                    continue;
+                }
+            },
+            kind => {
+                errors.record(addr, &ProtocolError::UnexpectedKind(kind));
+                continue;
+            }
+        };
+
+        let room = rooms.entry(handshake.room.clone()).or_default();
+        match MessageType::from(handshake.role) {
+            MessageType::Host => {
+                match room.live_host(now) {
+                    // Re-registration from the current host, just resend the clients
+                    Some(host_addr) if host_addr == addr => {}
+                    // The room is taken by a host that is still alive, do nothing
+                    Some(host_addr) => {
+                        eprintln!("Host {} tried to open room {:?} already hosted by {}", addr, handshake.room, host_addr);
+                        let reason = format!("room {:?} already has a host", handshake.room);
+                        protocol::send_datagram(&conn, &protocol::encode(MessageKind::RelayReject, &reason), addr).await;
+                        continue;
+                    }
+                    // Free room, or its previous host expired and is being taken over
+                    None => {
+                        // Send host info to each client
+                        let host_info = protocol::encode(MessageKind::HostInfo, &addr);
+                        for client in room.clients.keys() {
+                            protocol::send_datagram(&conn, &host_info, *client).await;
+                        }
+                        println!("Host {} opened room {:?}", addr, handshake.room);
+                    }
+                }
+                room.host = Some((addr, now));
+                protocol::send_datagram(&conn, &protocol::encode_empty(MessageKind::RelayAck), addr).await;
+
+                // Send list of clients to host address to punch through to
+                let clients_msg = protocol::encode(MessageKind::ClientList, &room.client_list());
+                protocol::send_datagram(&conn, &clients_msg, addr).await;
+            },
+            MessageType::Client => {
+                let known = room.clients.insert(addr, now).is_some();
+
+                // Exchange host and client information. The host info doubles
+                // as the ack, otherwise the client waits for a host to show up.
+                if let Some(host_addr) = room.live_host(now) {
+                    let host_info = protocol::encode(MessageKind::HostInfo, &host_addr);
+                    protocol::send_datagram(&conn, &host_info, addr).await;
+                    // A retrying client only needs the answer it missed
+                    if !known {
+                        let client_info = protocol::encode(MessageKind::ClientList, &vec![addr]);
+                        protocol::send_datagram(&conn, &client_info, host_addr).await;
+                    }
+                } else {
+                    protocol::send_datagram(&conn, &protocol::encode_empty(MessageKind::RelayAck), addr).await;
+                }
+                if !known {
+                    println!("Client {} joined room {:?}", addr, handshake.room);
+                }
+            }
+            MessageType::Other => {
+                let reason = format!("unknown role {}", handshake.role);
+                protocol::send_datagram(&conn, &protocol::encode(MessageKind::RelayReject, &reason), addr).await;
+                errors.record(addr, &ProtocolError::Malformed(reason));
+            }
+        }
+    }
+}
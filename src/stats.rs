@@ -0,0 +1,132 @@
+use std::{collections::VecDeque, fmt, time::Duration};
+use tokio::time::Instant;
+
+/// How long a ping may stay unanswered before it counts as lost.
+const PONG_DEADLINE: Duration = Duration::from_secs(1);
+
+/// Round trip time, jitter and loss of the link to the host, measured with the
+/// pings piggybacked on heartbeats. Everything but the smoothed jitter starts
+/// over with every report.
+pub(crate) struct LinkStats {
+    epoch: Instant,
+    next_ping: u64,
+    /// Pings that may still be answered, oldest first
+    pending: VecDeque<(u64, Instant, bool)>,
+    answered: u64,
+    lost: u64,
+    rtt_sum: Duration,
+    rtt_min: Option<Duration>,
+    rtt_max: Duration,
+    last_rtt: Option<Duration>,
+    /// Smoothed difference between consecutive round trips, like RTP's interarrival jitter
+    jitter: Duration,
+}
+
+impl LinkStats {
+    pub fn new() -> Self {
+        LinkStats {
+            epoch: Instant::now(),
+            next_ping: 0,
+            pending: VecDeque::new(),
+            answered: 0,
+            lost: 0,
+            rtt_sum: Duration::ZERO,
+            rtt_min: None,
+            rtt_max: Duration::ZERO,
+            last_rtt: None,
+            jitter: Duration::ZERO,
+        }
+    }
+
+    /// Numbers and timestamps the next ping. The timestamp only means something
+    /// to us, the host just echoes it.
+    pub fn ping(&mut self) -> (u64, u64) {
+        let ping = self.next_ping;
+        self.next_ping += 1;
+        let now = Instant::now();
+        self.settle(now);
+        self.pending.push_back((ping, now, false));
+        (ping, now.duration_since(self.epoch).as_micros() as u64)
+    }
+
+    /// Records the host's answer to `ping`, which carries our `sent_at` back.
+    pub fn pong(&mut self, ping: u64, sent_at: u64) {
+        // Answers to pings that were already counted as lost or answered are duplicates
+        let Some((_, _, answered)) = self.pending.iter_mut().find(|(pending, _, answered)| *pending == ping && !answered) else {
+            return;
+        };
+        *answered = true;
+
+        let rtt = Instant::now().duration_since(self.epoch).saturating_sub(Duration::from_micros(sent_at));
+        self.answered += 1;
+        self.rtt_sum += rtt;
+        self.rtt_min = Some(self.rtt_min.map_or(rtt, |min| min.min(rtt)));
+        self.rtt_max = self.rtt_max.max(rtt);
+        if let Some(last) = self.last_rtt {
+            self.jitter = (self.jitter * 15 + rtt.abs_diff(last)) / 16;
+        }
+        self.last_rtt = Some(rtt);
+    }
+
+    /// Forgets answered pings and counts the ones past their deadline as lost.
+    fn settle(&mut self, now: Instant) {
+        while let Some(&(_, sent, answered)) = self.pending.front() {
+            if !answered && now.duration_since(sent) < PONG_DEADLINE {
+                break;
+            }
+            if !answered {
+                self.lost += 1;
+            }
+            self.pending.pop_front();
+        }
+    }
+
+    /// Returns a summary of everything since the previous report.
+    pub fn report(&mut self) -> LinkReport {
+        self.settle(Instant::now());
+
+        let report = LinkReport {
+            answered: self.answered,
+            lost: self.lost,
+            rtt_mean: (self.answered > 0).then(|| self.rtt_sum / self.answered as u32),
+            rtt_min: self.rtt_min,
+            rtt_max: self.rtt_max,
+            jitter: self.jitter,
+        };
+        self.answered = 0;
+        self.lost = 0;
+        self.rtt_sum = Duration::ZERO;
+        self.rtt_min = None;
+        self.rtt_max = Duration::ZERO;
+        report
+    }
+}
+
+pub(crate) struct LinkReport {
+    answered: u64,
+    lost: u64,
+    rtt_mean: Option<Duration>,
+    rtt_min: Option<Duration>,
+    rtt_max: Duration,
+    jitter: Duration,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl fmt::Display for LinkReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.rtt_mean, self.rtt_min) {
+            (Some(mean), Some(min)) => write!(
+                f,
+                "rtt {:.1} ms (min {:.1}, max {:.1}), jitter {:.1} ms",
+                millis(mean), millis(min), millis(self.rtt_max), millis(self.jitter)
+            )?,
+            _ => write!(f, "no answers")?,
+        }
+        let settled = self.answered + self.lost;
+        let loss = if settled > 0 { self.lost as f64 * 100.0 / settled as f64 } else { 0.0 };
+        write!(f, ", loss {:.1}% ({} of {})", loss, self.lost, settled)
+    }
+}